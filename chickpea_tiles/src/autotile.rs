@@ -0,0 +1,500 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::Path;
+
+use {Error, OutputTileFormat, TileSetResult};
+
+// neighbor bits of a mask, with north pointing up (towards smaller y)
+pub const NORTH: u8 = 1 << 0;
+pub const NORTH_EAST: u8 = 1 << 1;
+pub const EAST: u8 = 1 << 2;
+pub const SOUTH_EAST: u8 = 1 << 3;
+pub const SOUTH: u8 = 1 << 4;
+pub const SOUTH_WEST: u8 = 1 << 5;
+pub const WEST: u8 = 1 << 6;
+pub const NORTH_WEST: u8 = 1 << 7;
+
+pub const CARDINALS: u8 = NORTH | EAST | SOUTH | WEST;
+
+/// The offset of every neighbor bit, in bit order.
+pub const OFFSETS: [(u8, [i32; 2]); 8] = [(NORTH, [0, -1]),
+                                           (NORTH_EAST, [1, -1]),
+                                           (EAST, [1, 0]),
+                                           (SOUTH_EAST, [1, 1]),
+                                           (SOUTH, [0, 1]),
+                                           (SOUTH_WEST, [-1, 1]),
+                                           (WEST, [-1, 0]),
+                                           (NORTH_WEST, [-1, -1])];
+
+pub fn direction_bit(name: &str) -> Option<u8> {
+    match name {
+        "n" => Some(NORTH),
+        "ne" => Some(NORTH_EAST),
+        "e" => Some(EAST),
+        "se" => Some(SOUTH_EAST),
+        "s" => Some(SOUTH),
+        "sw" => Some(SOUTH_WEST),
+        "w" => Some(WEST),
+        "nw" => Some(NORTH_WEST),
+        _ => None,
+    }
+}
+
+/// Builds the mask of a cell, `occupied` is given the offset of each neighbor.
+pub fn neighbor_mask<F: Fn([i32; 2]) -> bool>(occupied: F) -> u8 {
+    OFFSETS.iter().fold(0, |mask, &(bit, offset)| if occupied(offset) {
+        mask | bit
+    } else {
+        mask
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Only the cardinal neighbors matter.
+    Four,
+    Eight,
+}
+
+impl Connectivity {
+    pub fn from_neighbors(neighbors: u8) -> Option<Connectivity> {
+        match neighbors {
+            4 => Some(Connectivity::Four),
+            8 => Some(Connectivity::Eight),
+            _ => None,
+        }
+    }
+
+    pub fn neighbors(self) -> u8 {
+        match self {
+            Connectivity::Four => 4,
+            Connectivity::Eight => 8,
+        }
+    }
+
+    pub fn relevant_bits(self) -> u8 {
+        match self {
+            Connectivity::Four => CARDINALS,
+            Connectivity::Eight => 0xff,
+        }
+    }
+}
+
+/// One tile of an item, as a part of its output format and an index into it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TileRef {
+    pub part: String,
+    pub index: usize,
+}
+
+impl TileRef {
+    pub fn new(part: &str, index: usize) -> TileRef {
+        TileRef {
+            part: String::from(part),
+            index: index,
+        }
+    }
+}
+
+/// Autotile rules as written in an output format. For every mask the first
+/// rule whose `require`d neighbors are all present and whose `forbid`den
+/// neighbors are all absent decides the tile; neighbors are named by
+/// compass direction ("n", "ne", "e", ...).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AutotileSource {
+    /// Either 4 or 8.
+    pub connectivity: u8,
+    pub rules: Vec<AutotileRule>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AutotileRule {
+    #[serde(default)]
+    pub require: Vec<String>,
+    #[serde(default)]
+    pub forbid: Vec<String>,
+    pub part: String,
+    #[serde(default)]
+    pub index: usize,
+}
+
+/// Autotile rules in the compact form stored in compiled tile sets.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AutotileTable {
+    pub connectivity: u8,
+    /// For every mask, the part (by its position in the output format) and
+    /// the index into the part.
+    pub tiles: Vec<[usize; 2]>,
+}
+
+impl AutotileTable {
+    pub fn resolve(&self, mask: u8) -> [usize; 2] {
+        self.tiles[mask as usize]
+    }
+}
+
+/// Decides which tile of an autotiled item to draw for every possible
+/// combination of neighbors.
+#[derive(Clone, Debug)]
+pub struct AutotileRules {
+    connectivity: Connectivity,
+    // indexed by the whole mask, bits that don't matter are already
+    // accounted for when the table is built
+    table: Vec<TileRef>,
+}
+
+impl AutotileRules {
+    pub fn from_fn<F: Fn(u8) -> TileRef>(connectivity: Connectivity, f: F) -> AutotileRules {
+        let bits = connectivity.relevant_bits();
+        AutotileRules {
+            connectivity: connectivity,
+            table: (0..256).map(|mask| f(mask as u8 & bits)).collect(),
+        }
+    }
+
+    /// Compiles the rules of the output format at `path`, checking that
+    /// they cover every mask and only refer to tiles that exist.
+    pub fn from_source(path: &Path,
+                       fmt: &OutputTileFormat,
+                       source: &AutotileSource)
+                       -> TileSetResult<AutotileRules> {
+        let connectivity = match Connectivity::from_neighbors(source.connectivity) {
+            Some(connectivity) => connectivity,
+            None => {
+                return Err(Error::BadConnectivity {
+                    path: path.to_path_buf(),
+                    connectivity: source.connectivity,
+                })
+            }
+        };
+        let bits = connectivity.relevant_bits();
+
+        let directions = |rule: usize, names: &[String]| -> TileSetResult<u8> {
+            let mut mask = 0;
+            for name in names {
+                match direction_bit(name) {
+                    Some(bit) if bit & bits != 0 => mask |= bit,
+                    _ => {
+                        return Err(Error::UnknownDirection {
+                            path: path.to_path_buf(),
+                            rule: rule,
+                            direction: name.clone(),
+                        })
+                    }
+                }
+            }
+            Ok(mask)
+        };
+
+        let mut compiled = Vec::with_capacity(source.rules.len());
+        for (i, rule) in source.rules.iter().enumerate() {
+            let require = try!(directions(i, &rule.require[..]));
+            let forbid = try!(directions(i, &rule.forbid[..]));
+            if fmt.parts.get(&rule.part).map_or(true, |&count| rule.index >= count) {
+                return Err(Error::RuleTileOutOfRange {
+                    path: path.to_path_buf(),
+                    rule: i,
+                    part: rule.part.clone(),
+                    index: rule.index,
+                });
+            }
+            compiled.push((require, forbid, TileRef::new(&rule.part, rule.index)));
+        }
+
+        let mut table = Vec::with_capacity(256);
+        for mask in 0..256 {
+            let mask = mask as u8 & bits;
+            let found = compiled.iter()
+                .find(|&&(require, forbid, _)| mask & require == require && mask & forbid == 0);
+            match found {
+                Some(&(_, _, ref tile)) => table.push(tile.clone()),
+                None => {
+                    return Err(Error::UncoveredMask {
+                        path: path.to_path_buf(),
+                        mask: mask,
+                    })
+                }
+            }
+        }
+
+        Ok(AutotileRules {
+            connectivity: connectivity,
+            table: table,
+        })
+    }
+
+    /// The compact form of these rules, for a tile set compiled with `fmt`.
+    pub fn to_table(&self, fmt: &OutputTileFormat) -> AutotileTable {
+        let parts: Vec<&String> = fmt.parts.keys().collect();
+        AutotileTable {
+            connectivity: self.connectivity.neighbors(),
+            tiles: self.table
+                .iter()
+                .map(|tile| [parts.iter().position(|&p| *p == tile.part).unwrap_or(0), tile.index])
+                .collect(),
+        }
+    }
+
+    pub fn connectivity(&self) -> Connectivity {
+        self.connectivity
+    }
+
+    pub fn resolve(&self, mask: u8) -> &TileRef {
+        &self.table[mask as usize]
+    }
+
+    /// The rules for one of the bundled output formats, recognized by their parts.
+    pub fn for_format(fmt: &OutputTileFormat) -> Option<AutotileRules> {
+        let parts: Vec<(&str, usize)> = fmt.parts.iter().map(|(k, &v)| (&k[..], v)).collect();
+        // output formats are sorted by part name
+        if parts == [("closed_center", 1), ("left_right", 3), ("numpad", 9), ("top_bottom", 3)] {
+            Some(AutotileRules::floor())
+        } else if parts == [("center_point", 1), ("circle", 6), ("cross", 5), ("wall", 1)] {
+            Some(AutotileRules::wall())
+        } else {
+            None
+        }
+    }
+
+    /// Floors join up with their cardinal neighbors. Areas use the `numpad`
+    /// part (laid out like a numeric keypad, starting from 1), one tile wide
+    /// strips use `top_bottom` or `left_right` and single tiles `closed_center`.
+    pub fn floor() -> AutotileRules {
+        AutotileRules::from_fn(Connectivity::Four, |mask| {
+            let n = mask & NORTH != 0;
+            let e = mask & EAST != 0;
+            let s = mask & SOUTH != 0;
+            let w = mask & WEST != 0;
+            let end = |before: bool, after: bool| match (before, after) {
+                (false, true) => 0,
+                (true, true) => 1,
+                _ => 2,
+            };
+
+            match (n || s, e || w) {
+                (false, false) => TileRef::new("closed_center", 0),
+                (true, false) => TileRef::new("top_bottom", end(n, s)),
+                (false, true) => TileRef::new("left_right", end(w, e)),
+                (true, true) => {
+                    // numpad rows go bottom to top
+                    let row = 2 - end(n, s);
+                    TileRef::new("numpad", row * 3 + end(w, e))
+                }
+            }
+        })
+    }
+
+    /// Walls are drawn as lines connecting cardinal neighbors: `circle` holds
+    /// the straight pieces and corners, `cross` the junctions and
+    /// `center_point` a lone pillar. A wall surrounded by walls on all eight
+    /// sides is solid, and uses `wall`.
+    pub fn wall() -> AutotileRules {
+        AutotileRules::from_fn(Connectivity::Eight, |mask| {
+            if mask == 0xff {
+                return TileRef::new("wall", 0);
+            }
+            let n = mask & NORTH != 0;
+            let e = mask & EAST != 0;
+            let s = mask & SOUTH != 0;
+            let w = mask & WEST != 0;
+            match (n, e, s, w) {
+                (false, false, false, false) => TileRef::new("center_point", 0),
+                (true, true, false, false) => TileRef::new("circle", 0),
+                (_, false, _, false) => TileRef::new("circle", 1),
+                (false, true, true, false) => TileRef::new("circle", 2),
+                (false, _, false, _) => TileRef::new("circle", 3),
+                (false, false, true, true) => TileRef::new("circle", 4),
+                (true, false, false, true) => TileRef::new("circle", 5),
+                (false, true, true, true) => TileRef::new("cross", 0),
+                (true, true, true, false) => TileRef::new("cross", 1),
+                (true, true, true, true) => TileRef::new("cross", 2),
+                (true, false, true, true) => TileRef::new("cross", 3),
+                (true, true, false, true) => TileRef::new("cross", 4),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::path::Path;
+    use {load, Error, OutputTileFormat, TileSetResult};
+
+    fn output_format(name: &str) -> OutputTileFormat {
+        load(Path::new("test_data/src/output_tile_formats").join(name))
+            .expect("couldn't read output format")
+    }
+
+    fn mask(n: bool, e: bool, s: bool, w: bool) -> u8 {
+        (if n { NORTH } else { 0 }) | (if e { EAST } else { 0 }) | (if s { SOUTH } else { 0 }) |
+        (if w { WEST } else { 0 })
+    }
+
+    // every mask resolves to a tile that exists, and every tile is used
+    fn check_complete(rules: &AutotileRules, fmt: &OutputTileFormat) {
+        let mut used = HashSet::new();
+        for m in 0..256 {
+            let tile = rules.resolve(m as u8);
+            let count = *fmt.parts.get(&tile.part).expect("resolved to an unknown part");
+            assert!(tile.index < count, "mask {:08b} resolved to {:?}", m, tile);
+            used.insert(tile.clone());
+        }
+        assert_eq!(used.len(), fmt.parts.values().fold(0, |x, y| x + y));
+    }
+
+    #[test]
+    fn bundled_formats_are_recognized() {
+        let floor = AutotileRules::for_format(&output_format("floor")).expect("floor not recognized");
+        let wall = AutotileRules::for_format(&output_format("wall")).expect("wall not recognized");
+        assert_eq!(floor.connectivity(), Connectivity::Four);
+        assert_eq!(wall.connectivity(), Connectivity::Eight);
+
+        let mut other = output_format("floor");
+        other.parts.insert(String::from("extra"), 1);
+        assert!(AutotileRules::for_format(&other).is_none());
+    }
+
+    #[test]
+    fn floor_masks() {
+        let rules = AutotileRules::floor();
+        check_complete(&rules, &output_format("floor"));
+
+        let expected = [// n, e, s, w
+                        ((false, false, false, false), ("closed_center", 0)),
+                        ((true, false, false, false), ("top_bottom", 2)),
+                        ((false, false, true, false), ("top_bottom", 0)),
+                        ((true, false, true, false), ("top_bottom", 1)),
+                        ((false, true, false, false), ("left_right", 0)),
+                        ((false, false, false, true), ("left_right", 2)),
+                        ((false, true, false, true), ("left_right", 1)),
+                        ((false, true, true, false), ("numpad", 6)),
+                        ((false, true, true, true), ("numpad", 7)),
+                        ((false, false, true, true), ("numpad", 8)),
+                        ((true, true, true, false), ("numpad", 3)),
+                        ((true, true, true, true), ("numpad", 4)),
+                        ((true, false, true, true), ("numpad", 5)),
+                        ((true, true, false, false), ("numpad", 0)),
+                        ((true, true, false, true), ("numpad", 1)),
+                        ((true, false, false, true), ("numpad", 2))];
+        assert_eq!(expected.len(), 16);
+        for &((n, e, s, w), (part, index)) in &expected {
+            let m = mask(n, e, s, w);
+            assert_eq!(*rules.resolve(m), TileRef::new(part, index));
+            // diagonals never matter for floors
+            for diagonals in 0..16u8 {
+                let extra = (diagonals & 1) * NORTH_EAST + (diagonals >> 1 & 1) * SOUTH_EAST +
+                            (diagonals >> 2 & 1) * SOUTH_WEST +
+                            (diagonals >> 3 & 1) * NORTH_WEST;
+                assert_eq!(rules.resolve(m | extra), rules.resolve(m));
+            }
+        }
+    }
+
+    #[test]
+    fn wall_masks() {
+        let rules = AutotileRules::wall();
+        check_complete(&rules, &output_format("wall"));
+
+        let expected = [// n, e, s, w
+                        ((false, false, false, false), ("center_point", 0)),
+                        ((true, false, false, false), ("circle", 1)),
+                        ((false, false, true, false), ("circle", 1)),
+                        ((true, false, true, false), ("circle", 1)),
+                        ((false, true, false, false), ("circle", 3)),
+                        ((false, false, false, true), ("circle", 3)),
+                        ((false, true, false, true), ("circle", 3)),
+                        ((true, true, false, false), ("circle", 0)),
+                        ((false, true, true, false), ("circle", 2)),
+                        ((false, false, true, true), ("circle", 4)),
+                        ((true, false, false, true), ("circle", 5)),
+                        ((false, true, true, true), ("cross", 0)),
+                        ((true, true, true, false), ("cross", 1)),
+                        ((true, true, true, true), ("cross", 2)),
+                        ((true, false, true, true), ("cross", 3)),
+                        ((true, true, false, true), ("cross", 4))];
+        assert_eq!(expected.len(), 16);
+        for &((n, e, s, w), (part, index)) in &expected {
+            assert_eq!(*rules.resolve(mask(n, e, s, w)), TileRef::new(part, index));
+        }
+
+        assert_eq!(*rules.resolve(0xff), TileRef::new("wall", 0));
+        assert_eq!(*rules.resolve(0xff & !NORTH_WEST), TileRef::new("cross", 2));
+    }
+
+    fn from_json(name: &str) -> TileSetResult<AutotileRules> {
+        let fmt = output_format(name);
+        let source = fmt.autotile.clone().expect("no autotile rules");
+        AutotileRules::from_source(Path::new(name), &fmt, &source)
+    }
+
+    #[test]
+    fn bundled_rules_match_builtins() {
+        let pairs = [("floor", AutotileRules::floor()), ("wall", AutotileRules::wall())];
+        for &(name, ref builtin) in &pairs {
+            let rules = from_json(name).expect("rules didn't compile");
+            assert_eq!(rules.connectivity(), builtin.connectivity());
+            for m in 0..256 {
+                assert_eq!(rules.resolve(m as u8), builtin.resolve(m as u8), "mask {:08b}", m);
+            }
+        }
+
+        let fmt = output_format("floor");
+        let table = AutotileRules::floor().to_table(&fmt);
+        // parts in output format order: closed_center, left_right, numpad, top_bottom
+        assert_eq!(table.resolve(0), [0, 0]);
+        assert_eq!(table.resolve(NORTH | EAST | SOUTH | WEST), [2, 4]);
+        assert_eq!(table.resolve(SOUTH), [3, 0]);
+    }
+
+    #[test]
+    fn bad_rules_are_rejected() {
+        let mut fmt = output_format("floor");
+        let mut source = fmt.autotile.take().expect("no autotile rules");
+        let path = Path::new("floor.json");
+
+        source.rules.pop();
+        match AutotileRules::from_source(path, &fmt, &source) {
+            Err(Error::UncoveredMask { mask, .. }) => assert_eq!(mask, SOUTH | WEST),
+            _ => panic!("expected an uncovered mask"),
+        }
+
+        source.rules[0].forbid.push(String::from("ne"));
+        match AutotileRules::from_source(path, &fmt, &source) {
+            Err(Error::UnknownDirection { rule, ref direction, .. }) => {
+                assert_eq!((rule, &direction[..]), (0, "ne"))
+            }
+            _ => panic!("expected an unknown direction"),
+        }
+
+        source.rules[0].forbid.pop();
+        source.rules[1].index = 3;
+        match AutotileRules::from_source(path, &fmt, &source) {
+            Err(Error::RuleTileOutOfRange { rule, index, .. }) => assert_eq!((rule, index), (1, 3)),
+            _ => panic!("expected a tile out of range"),
+        }
+    }
+
+    #[test]
+    fn masks_from_occupancy() {
+        assert_eq!(neighbor_mask(|_| false), 0);
+        assert_eq!(neighbor_mask(|_| true), 0xff);
+        assert_eq!(neighbor_mask(|o| o[1] < 0), NORTH | NORTH_EAST | NORTH_WEST);
+        assert_eq!(neighbor_mask(|o| o == [1, 0]), EAST);
+    }
+}
@@ -0,0 +1,196 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate chickpea_tiles;
+
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use chickpea_tiles::{BuildStatus, CompileStats};
+
+const USAGE: &'static str = "\
+usage: chickpea-tiles [options] <src> <target> [tile_set_source...]
+
+Compiles the given tile set sources (by default every one in
+<src>/tile_set_sources) from the <src> folder into the <target> folder.
+
+options:
+    -v, --verbose   print what happened to every tile set
+    -n, --dry-run   only check the tile set sources, don't write anything
+    -f, --force     recompile even when a tile set is up to date
+    -w, --watch     keep running, and recompile tile sets whenever their
+                    sources, formats or images change
+    -t, --tsx       also write a Tiled tileset next to every atlas page
+    -h, --help      print this message";
+
+struct Options {
+    verbose: bool,
+    dry_run: bool,
+    force: bool,
+    watch: bool,
+    tsx: bool,
+    src: PathBuf,
+    target: PathBuf,
+    sources: Vec<PathBuf>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut verbose = false;
+    let mut dry_run = false;
+    let mut force = false;
+    let mut watch = false;
+    let mut tsx = false;
+    let mut positional = Vec::new();
+
+    for arg in args {
+        match &arg[..] {
+            "-v" | "--verbose" => verbose = true,
+            "-n" | "--dry-run" => dry_run = true,
+            "-f" | "--force" => force = true,
+            "-w" | "--watch" => watch = true,
+            "-t" | "--tsx" => tsx = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("-") => return Err(format!("unknown option `{}`", arg)),
+            _ => positional.push(PathBuf::from(arg)),
+        }
+    }
+
+    if positional.len() < 2 {
+        return Err(String::from("expected a source and a target folder"));
+    }
+    let sources = positional.split_off(2);
+    let target = positional.pop().unwrap();
+    let src = positional.pop().unwrap();
+
+    Ok(Options {
+        verbose: verbose,
+        dry_run: dry_run,
+        force: force,
+        watch: watch,
+        tsx: tsx,
+        src: src,
+        target: target,
+        sources: sources,
+    })
+}
+
+fn describe(stats: &CompileStats) -> String {
+    format!("{} page(s), {} of {} tiles unique ({} bytes saved), {:.1}% utilization",
+            stats.pages.len(),
+            stats.unique_tiles,
+            stats.tiles,
+            stats.bytes_saved,
+            stats.utilization * 100.0)
+}
+
+// returns whether the tile set compiled successfully
+fn run_one(opts: &Options, source: &Path) -> bool {
+    let target = chickpea_tiles::tile_set_target_path(source);
+
+    let result = if opts.dry_run {
+        chickpea_tiles::check_tile_set(&opts.src, source)
+            .map(|stats| format!("{} is ok: {}", source.display(), describe(&stats)))
+    } else if opts.force {
+        chickpea_tiles::compile_tile_set(&opts.src, source, &opts.target, &target)
+            .map(|stats| format!("compiled {}: {}", source.display(), describe(&stats)))
+    } else {
+        chickpea_tiles::build_tile_set(&opts.src, source, &opts.target, &target).map(|status| {
+            match status {
+                BuildStatus::UpToDate => format!("{} is up to date", source.display()),
+                BuildStatus::Compiled(stats) => {
+                    format!("compiled {}: {}", source.display(), describe(&stats))
+                }
+            }
+        })
+    };
+
+    let result = result.and_then(|msg| {
+        if opts.tsx && !opts.dry_run {
+            let ts_path = opts.target.join(&target);
+            chickpea_tiles::tiled::export(&ts_path).map(|_| msg)
+        } else {
+            Ok(msg)
+        }
+    });
+
+    match result {
+        Ok(msg) => {
+            if opts.verbose || opts.watch {
+                println!("{}", msg);
+            }
+            true
+        }
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "error: {}", err);
+            false
+        }
+    }
+}
+
+fn watch(opts: &Options) -> ! {
+    let mut watcher = chickpea_tiles::Watcher::new(&opts.src);
+    loop {
+        for source in watcher.poll() {
+            if opts.sources.is_empty() || opts.sources.iter().any(|s| s.with_extension("") == source) {
+                run_one(opts, &source);
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+fn main() {
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            if msg.is_empty() {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            let _ = writeln!(io::stderr(), "error: {}\n\n{}", msg, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if opts.watch {
+        watch(&opts);
+    }
+
+    let sources = if opts.sources.is_empty() {
+        match chickpea_tiles::find_tile_set_sources(&opts.src) {
+            Ok(sources) => sources,
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "error: {}", err);
+                process::exit(1);
+            }
+        }
+    } else {
+        opts.sources.clone()
+    };
+
+    let failures = sources.iter().filter(|source| !run_one(&opts, source)).count();
+    if failures > 0 {
+        let _ = writeln!(io::stderr(),
+                         "{} of {} tile set(s) failed to compile",
+                         failures,
+                         sources.len());
+        process::exit(1);
+    }
+}
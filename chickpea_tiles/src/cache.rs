@@ -0,0 +1,180 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use serde_json;
+
+use {compile_tile_set, json_path, load, CompileStats, Error, InputTileFormat, TileSetResult,
+     TileSetSource, TileSource};
+use hash;
+
+// bump whenever the compiler's output changes for the same inputs
+const CACHE_VERSION: u32 = 3;
+
+#[derive(PartialEq, Serialize, Deserialize)]
+struct BuildCache {
+    version: u32,
+    inputs: BTreeMap<String, u64>,
+    outputs: BTreeMap<String, u64>,
+}
+
+pub enum BuildStatus {
+    UpToDate,
+    Compiled(CompileStats),
+}
+
+/// Every file a tile set source depends on, itself included: the tile
+/// sources (animation frames' too), their images, and the input and output
+/// formats.
+pub fn dependencies(src_folder: &Path, tile_set_source_path: &Path) -> TileSetResult<Vec<PathBuf>> {
+    let tss_path = json_path(src_folder.join(tile_set_source_path));
+    let tss: TileSetSource = try!(load(tss_path.clone()));
+
+    let mut deps = BTreeSet::new();
+    deps.insert(tss_path);
+    let mut froms = BTreeSet::new();
+    for group in &tss.groups {
+        let ifmt: InputTileFormat = try!(load(src_folder.join(&group.fmt)));
+        froms.insert(&group.from);
+        for item in &group.items {
+            froms.extend(item.frames.iter().filter_map(|f| f.from.as_ref()));
+        }
+        deps.insert(json_path(src_folder.join(&group.fmt)));
+        deps.insert(json_path(src_folder.join(&ifmt.fmt)));
+    }
+    for from in froms {
+        let source: TileSource = try!(load(src_folder.join(from)));
+        deps.insert(json_path(src_folder.join(from)));
+        deps.insert(src_folder.join(&source.image_path));
+    }
+    Ok(deps.into_iter().collect())
+}
+
+fn hash_file(path: &Path) -> TileSetResult<u64> {
+    let mut bytes = Vec::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|err| Error::IOError(path.to_path_buf(), err)));
+    Ok(hash::fnv64(&bytes))
+}
+
+fn hash_files<'a, I>(paths: I) -> TileSetResult<BTreeMap<String, u64>>
+    where I: Iterator<Item = &'a PathBuf>
+{
+    let mut hashes = BTreeMap::new();
+    for path in paths {
+        hashes.insert(path.to_string_lossy().into_owned(), try!(hash_file(path)));
+    }
+    Ok(hashes)
+}
+
+fn cache_path(target: &Path, tile_set_target_path: &Path) -> PathBuf {
+    json_path(target.join(tile_set_target_path)).with_extension("cache")
+}
+
+// not `load`, which would swap the extension back to .json
+fn read_cache(path: &Path) -> TileSetResult<BuildCache> {
+    let reader = try!(File::open(path).map_err(|err| Error::IOError(path.to_path_buf(), err)));
+    serde_json::de::from_reader(reader).map_err(|err| Error::JsonError(path.to_path_buf(), err))
+}
+
+// the outputs count as up to date only if none were removed or touched since
+fn outputs_unchanged(cache: &BuildCache) -> bool {
+    cache.outputs.iter().all(|(path, &expected)| {
+        hash_file(Path::new(path)).map(|h| h == expected).unwrap_or(false)
+    })
+}
+
+/// Compiles a tile set like `compile_tile_set`, unless none of its inputs or
+/// outputs changed since the last time it was built.
+pub fn build_tile_set(src_folder: &Path,
+                      tile_set_source_path: &Path,
+                      target: &Path,
+                      tile_set_target_path: &Path)
+                      -> TileSetResult<BuildStatus> {
+    let deps = try!(dependencies(src_folder, tile_set_source_path));
+    let inputs = try!(hash_files(deps.iter()));
+
+    let cache_path = cache_path(target, tile_set_target_path);
+    if let Ok(cache) = read_cache(&cache_path) {
+        if cache.version == CACHE_VERSION && cache.inputs == inputs && outputs_unchanged(&cache) {
+            return Ok(BuildStatus::UpToDate);
+        }
+    }
+
+    let stats = try!(compile_tile_set(src_folder, tile_set_source_path, target, tile_set_target_path));
+    let cache = BuildCache {
+        version: CACHE_VERSION,
+        inputs: inputs,
+        outputs: try!(hash_files(stats.outputs.iter())),
+    };
+
+    let mut writer = try!(File::create(&cache_path)
+        .map_err(|err| Error::IOError(cache_path.clone(), err)));
+    try!(serde_json::ser::to_writer(&mut writer, &cache)
+        .map_err(|err| Error::JsonError(cache_path.clone(), err)));
+
+    Ok(BuildStatus::Compiled(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn build() -> BuildStatus {
+        build_tile_set(&Path::new("test_data/src"),
+                       &Path::new("tile_set_sources/morning"),
+                       &Path::new("test_data/target"),
+                       &Path::new("tile_sets/morning_cached"))
+            .expect("build failed")
+    }
+
+    #[test]
+    fn unchanged_tile_sets_are_skipped() {
+        let _ = fs::remove_file("test_data/target/tile_sets/morning_cached.cache");
+
+        assert!(match build() {
+            BuildStatus::Compiled(_) => true,
+            BuildStatus::UpToDate => false,
+        });
+        assert!(match build() {
+            BuildStatus::UpToDate => true,
+            BuildStatus::Compiled(_) => false,
+        });
+
+        fs::remove_file("test_data/target/tile_sets/morning_cached.0.png")
+            .expect("couldn't remove atlas");
+        assert!(match build() {
+            BuildStatus::Compiled(_) => true,
+            BuildStatus::UpToDate => false,
+        });
+    }
+
+    #[test]
+    fn dependencies_cover_every_input() {
+        let src = Path::new("test_data/src");
+        let deps = dependencies(src, Path::new("tile_set_sources/morning"))
+            .expect("couldn't find dependencies");
+        assert_eq!(deps.len(), 1 + 2 * 4);
+        assert!(deps.contains(&src.join("raw_images/DawnLike/Objects/Floor.png")));
+        assert!(deps.contains(&src.join("output_tile_formats/wall.json")));
+    }
+}
@@ -0,0 +1,403 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std;
+use std::fmt;
+use std::path::PathBuf;
+use image;
+use serde_json;
+use xml;
+
+use AtlasRect;
+
+/// Everything that can go wrong while compiling a tile set. Each variant
+/// carries the path of the file at fault, so it can be reported as-is.
+#[derive(Debug)]
+pub enum Error {
+    MissingPart {
+        path: PathBuf,
+        fmt: String,
+        part: String,
+    },
+    UnexpectedPart {
+        path: PathBuf,
+        fmt: String,
+        part: String,
+    },
+    PartDefinedTwice {
+        path: PathBuf,
+        fmt: String,
+        part: String,
+    },
+    TileCountMismatch {
+        path: PathBuf,
+        fmt: String,
+        part: String,
+        expected: usize,
+        found: usize,
+    },
+    DuplicateId {
+        path: PathBuf,
+        fmt: String,
+        id: String,
+    },
+    TileSizeMismatch {
+        path: PathBuf,
+        from: String,
+        expected: [usize; 2],
+        found: [usize; 2],
+    },
+    OddTileSize {
+        path: PathBuf,
+        tile_size: [usize; 2],
+    },
+    SourceTileOutOfBounds {
+        path: PathBuf,
+        image: PathBuf,
+        id: String,
+        part: String,
+        tile: [usize; 2],
+        image_size: [usize; 2],
+    },
+    BadFrameDuration {
+        path: PathBuf,
+        id: String,
+        frame: usize,
+    },
+    BadProperty {
+        path: PathBuf,
+        owner: String,
+        property: &'static str,
+    },
+    AtlasOverflow {
+        path: PathBuf,
+        max_size: [usize; 2],
+    },
+    UnknownPacker {
+        path: PathBuf,
+        name: String,
+    },
+    Invalid(Vec<Error>),
+    InconsistentParts {
+        path: PathBuf,
+        fmt: String,
+        id: String,
+    },
+    BadConnectivity {
+        path: PathBuf,
+        connectivity: u8,
+    },
+    UnknownDirection {
+        path: PathBuf,
+        rule: usize,
+        direction: String,
+    },
+    RuleTileOutOfRange {
+        path: PathBuf,
+        rule: usize,
+        part: String,
+        index: usize,
+    },
+    UncoveredMask {
+        path: PathBuf,
+        mask: u8,
+    },
+    BadAutotileTable {
+        path: PathBuf,
+        fmt: String,
+    },
+    AtlasRectOutOfBounds {
+        path: PathBuf,
+        fmt: String,
+        id: String,
+        part: String,
+        rect: AtlasRect,
+    },
+    BadTiledFile {
+        path: PathBuf,
+        reason: String,
+    },
+    AmbiguousTiledId {
+        path: PathBuf,
+        id: String,
+        fmts: [String; 2],
+    },
+    BadMapFile {
+        path: PathBuf,
+        reason: String,
+    },
+    UnsupportedMapVersion {
+        path: PathBuf,
+        version: u32,
+    },
+    UnknownMapItem {
+        path: PathBuf,
+        fmt: String,
+        id: String,
+    },
+    BadMapCell {
+        path: PathBuf,
+        layer: String,
+        pos: [usize; 2],
+    },
+    ImageError(PathBuf, image::ImageError),
+    JsonError(PathBuf, serde_json::error::Error),
+    IOError(PathBuf, std::io::Error),
+    XmlError(PathBuf, xml::reader::Error),
+}
+
+pub type TileSetResult<T> = Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MissingPart { ref path, ref fmt, ref part } => {
+                write!(f,
+                       "{}: part `{}` of output format `{}` is missing",
+                       path.display(),
+                       part,
+                       fmt)
+            }
+            Error::UnexpectedPart { ref path, ref fmt, ref part } => {
+                write!(f,
+                       "{}: part `{}` isn't in output format `{}`",
+                       path.display(),
+                       part,
+                       fmt)
+            }
+            Error::PartDefinedTwice { ref path, ref fmt, ref part } => {
+                write!(f,
+                       "{}: part `{}` of output format `{}` is both whole and composed",
+                       path.display(),
+                       part,
+                       fmt)
+            }
+            Error::TileCountMismatch { ref path, ref fmt, ref part, expected, found } => {
+                write!(f,
+                       "{}: part `{}` has {} tiles, but output format `{}` expects {}",
+                       path.display(),
+                       part,
+                       found,
+                       fmt,
+                       expected)
+            }
+            Error::DuplicateId { ref path, ref fmt, ref id } => {
+                write!(f,
+                       "{}: item `{}` is defined more than once for format `{}`",
+                       path.display(),
+                       id,
+                       fmt)
+            }
+            Error::TileSizeMismatch { ref path, ref from, expected, found } => {
+                write!(f,
+                       "{}: tile source `{}` has tile size {}x{}, expected {}x{}",
+                       path.display(),
+                       from,
+                       found[0],
+                       found[1],
+                       expected[0],
+                       expected[1])
+            }
+            Error::OddTileSize { ref path, tile_size } => {
+                write!(f,
+                       "{}: composed tiles need an even tile size, found {}x{}",
+                       path.display(),
+                       tile_size[0],
+                       tile_size[1])
+            }
+            Error::SourceTileOutOfBounds { ref path,
+                                           ref image,
+                                           ref id,
+                                           ref part,
+                                           tile,
+                                           image_size } => {
+                write!(f,
+                       "{}: tile [{}, {}] of item `{}`, part `{}` lies outside of {} ({}x{})",
+                       path.display(),
+                       tile[0],
+                       tile[1],
+                       id,
+                       part,
+                       image.display(),
+                       image_size[0],
+                       image_size[1])
+            }
+            Error::BadFrameDuration { ref path, ref id, frame } => {
+                write!(f,
+                       "{}: frame {} of item `{}` needs a duration above zero",
+                       path.display(),
+                       frame,
+                       id)
+            }
+            Error::BadProperty { ref path, ref owner, property } => {
+                write!(f,
+                       "{}: property `{}` of `{}` is invalid",
+                       path.display(),
+                       property,
+                       owner)
+            }
+            Error::AtlasOverflow { ref path, max_size } => {
+                write!(f,
+                       "{}: tiles don't fit into a {}x{} atlas",
+                       path.display(),
+                       max_size[0],
+                       max_size[1])
+            }
+            Error::UnknownPacker { ref path, ref name } => {
+                write!(f, "{}: unknown packer `{}`", path.display(), name)
+            }
+            Error::Invalid(ref errors) => {
+                try!(write!(f, "{} problem(s) found:", errors.len()));
+                for err in errors {
+                    try!(write!(f, "\n  {}", err));
+                }
+                Ok(())
+            }
+            Error::InconsistentParts { ref path, ref fmt, ref id } => {
+                write!(f,
+                       "{}: item `{}` has different parts than the rest of format `{}`",
+                       path.display(),
+                       id,
+                       fmt)
+            }
+            Error::BadConnectivity { ref path, connectivity } => {
+                write!(f,
+                       "{}: autotile connectivity must be 4 or 8, not {}",
+                       path.display(),
+                       connectivity)
+            }
+            Error::UnknownDirection { ref path, rule, ref direction } => {
+                write!(f,
+                       "{}: autotile rule {} uses unknown or ignored direction `{}`",
+                       path.display(),
+                       rule,
+                       direction)
+            }
+            Error::RuleTileOutOfRange { ref path, rule, ref part, index } => {
+                write!(f,
+                       "{}: autotile rule {} refers to tile {} of part `{}`, which doesn't exist",
+                       path.display(),
+                       rule,
+                       index,
+                       part)
+            }
+            Error::UncoveredMask { ref path, mask } => {
+                write!(f,
+                       "{}: no autotile rule matches neighbor mask {:08b}",
+                       path.display(),
+                       mask)
+            }
+            Error::BadAutotileTable { ref path, ref fmt } => {
+                write!(f,
+                       "{}: autotile table of format `{}` refers to missing tiles",
+                       path.display(),
+                       fmt)
+            }
+            Error::AtlasRectOutOfBounds { ref path, ref fmt, ref id, ref part, rect } => {
+                write!(f,
+                       "{}: rect {}x{}+{}+{} of item `{}` ({}), part `{}` lies outside of page {}",
+                       path.display(),
+                       rect.w,
+                       rect.h,
+                       rect.x,
+                       rect.y,
+                       id,
+                       fmt,
+                       part,
+                       rect.page)
+            }
+            Error::BadTiledFile { ref path, ref reason } => write!(f, "{}: {}", path.display(), reason),
+            Error::AmbiguousTiledId { ref path, ref id, ref fmts } => {
+                write!(f,
+                       "{}: item `{}` is in both `{}` and `{}`, which exported tiles can't tell apart",
+                       path.display(),
+                       id,
+                       fmts[0],
+                       fmts[1])
+            }
+            Error::BadMapFile { ref path, ref reason } => write!(f, "{}: {}", path.display(), reason),
+            Error::UnsupportedMapVersion { ref path, version } => {
+                write!(f, "{}: unsupported map version {}", path.display(), version)
+            }
+            Error::UnknownMapItem { ref path, ref fmt, ref id } => {
+                write!(f,
+                       "{}: item `{}` of format `{}` isn't in the tile set",
+                       path.display(),
+                       id,
+                       fmt)
+            }
+            Error::BadMapCell { ref path, ref layer, pos } => {
+                write!(f,
+                       "{}: cell {},{} of layer `{}` isn't in the palette",
+                       path.display(),
+                       pos[0],
+                       pos[1],
+                       layer)
+            }
+            Error::ImageError(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            Error::JsonError(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            Error::IOError(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            Error::XmlError(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::MissingPart { .. } => "missing part",
+            Error::UnexpectedPart { .. } => "unexpected part",
+            Error::PartDefinedTwice { .. } => "part defined twice",
+            Error::TileCountMismatch { .. } => "tile count mismatch",
+            Error::DuplicateId { .. } => "duplicate item id",
+            Error::TileSizeMismatch { .. } => "tile size mismatch",
+            Error::OddTileSize { .. } => "odd tile size",
+            Error::SourceTileOutOfBounds { .. } => "source tile out of bounds",
+            Error::BadFrameDuration { .. } => "bad frame duration",
+            Error::BadProperty { .. } => "bad tile property",
+            Error::AtlasOverflow { .. } => "atlas overflow",
+            Error::UnknownPacker { .. } => "unknown packer",
+            Error::Invalid(_) => "invalid tile set source",
+            Error::InconsistentParts { .. } => "inconsistent parts",
+            Error::BadConnectivity { .. } => "bad autotile connectivity",
+            Error::UnknownDirection { .. } => "unknown autotile direction",
+            Error::RuleTileOutOfRange { .. } => "autotile rule tile out of range",
+            Error::UncoveredMask { .. } => "uncovered autotile mask",
+            Error::BadAutotileTable { .. } => "bad autotile table",
+            Error::AtlasRectOutOfBounds { .. } => "atlas rect out of bounds",
+            Error::BadTiledFile { .. } => "bad tiled file",
+            Error::AmbiguousTiledId { .. } => "ambiguous tiled id",
+            Error::BadMapFile { .. } => "bad map file",
+            Error::UnsupportedMapVersion { .. } => "unsupported map version",
+            Error::UnknownMapItem { .. } => "unknown map item",
+            Error::BadMapCell { .. } => "bad map cell",
+            Error::ImageError(_, ref err) => err.description(),
+            Error::JsonError(_, ref err) => err.description(),
+            Error::IOError(_, ref err) => err.description(),
+            Error::XmlError(_, ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&std::error::Error> {
+        match *self {
+            Error::ImageError(_, ref err) => Some(err),
+            Error::JsonError(_, ref err) => Some(err),
+            Error::IOError(_, ref err) => Some(err),
+            Error::XmlError(_, ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
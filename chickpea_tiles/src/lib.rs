@@ -17,15 +17,40 @@
 #![feature(custom_derive, plugin)]
 #![plugin(serde_macros)]
 
+extern crate flate2;
 extern crate image;
+extern crate rustc_serialize;
 extern crate serde;
 extern crate serde_json;
+extern crate xml;
 
-use std::fs::File;
-use std::collections::HashMap;
+use std::fs::{self, File};
+use std::collections::{HashMap, HashSet};
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use image::{DynamicImage, GenericImage, ImageFormat};
+use std::hash::Hasher;
+use std::iter;
+use image::{DynamicImage, GenericImage, ImageFormat, RgbaImage};
+
+pub mod autotile;
+mod cache;
+mod error;
+mod hash;
+mod loader;
+pub mod map;
+pub mod pack;
+mod properties;
+pub mod tiled;
+mod watch;
+
+pub use cache::{build_tile_set, dependencies, BuildStatus};
+pub use error::{Error, TileSetResult};
+pub use loader::{FormatId, LoadedTileSet, TileId, UvRect};
+pub use map::TileMap;
+pub use properties::TileProperties;
+pub use watch::Watcher;
+
+pub const DEFAULT_MAX_ATLAS_SIZE: [usize; 2] = [4096, 4096];
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TileSource {
@@ -33,18 +58,104 @@ pub struct TileSource {
     pub tile_size: [usize; 2],
 }
 
-pub type OutputTileFormat = BTreeMap<String, usize>;
+#[derive(Clone, Serialize)]
+pub struct OutputTileFormat {
+    /// How many tiles every part has.
+    pub parts: BTreeMap<String, usize>,
+    pub autotile: Option<autotile::AutotileSource>,
+}
+
+#[derive(Deserialize)]
+struct OutputTileFormatFields {
+    parts: BTreeMap<String, usize>,
+    #[serde(default)]
+    autotile: Option<autotile::AutotileSource>,
+}
+
+impl serde::Deserialize for OutputTileFormat {
+    fn deserialize<D: serde::Deserializer>(deserializer: &mut D) -> Result<OutputTileFormat, D::Error> {
+        let custom = |err: serde_json::Error| <D::Error as serde::de::Error>::custom(err.to_string());
+        let value: serde_json::Value = try!(serde::Deserialize::deserialize(deserializer));
+        // formats used to be nothing but the map of parts, whose counts
+        // can't be mistaken for the new `parts` object
+        let bare = match value.find("parts") {
+            Some(&serde_json::Value::Object(_)) => false,
+            _ => true,
+        };
+        if !bare {
+            let fields: OutputTileFormatFields = try!(serde_json::value::from_value(value).map_err(custom));
+            Ok(OutputTileFormat {
+                parts: fields.parts,
+                autotile: fields.autotile,
+            })
+        } else {
+            let parts = try!(serde_json::value::from_value(value).map_err(custom));
+            Ok(OutputTileFormat {
+                parts: parts,
+                autotile: None,
+            })
+        }
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct InputTileFormat {
     pub fmt: String,
     pub parts: BTreeMap<String, Vec<[usize; 2]>>,
+    /// Parts whose tiles are put together from four quarter tiles, each
+    /// tile lists its top left, top right, bottom left and bottom right
+    /// quarters in half tile coordinates.
+    #[serde(default)]
+    pub composed: BTreeMap<String, Vec<[[usize; 2]; 4]>>,
+    /// Properties of every tile of a part, by part name.
+    #[serde(default)]
+    pub properties: BTreeMap<String, TileProperties>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TileSetSource {
     pub tile_size: [usize; 2],
     pub groups: Vec<TileSetSourceGroup>,
+    #[serde(default)]
+    pub atlas: AtlasOptions,
+}
+
+/// How a tile set's atlas gets packed, every field is optional.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct AtlasOptions {
+    /// Defaults to `DEFAULT_MAX_ATLAS_SIZE`, keep it within your GPU's texture limits.
+    #[serde(default)]
+    pub max_size: Option<[usize; 2]>,
+    /// Either "shelf" or "max_rects" (the default).
+    #[serde(default)]
+    pub packer: Option<String>,
+    /// Defaults to true.
+    #[serde(default)]
+    pub power_of_two: Option<bool>,
+}
+
+impl AtlasOptions {
+    pub fn pack_options(&self, path: &Path) -> TileSetResult<pack::PackOptions> {
+        let algorithm = match self.packer {
+            None => pack::Algorithm::MaxRects,
+            Some(ref name) => {
+                match pack::Algorithm::from_name(name) {
+                    Some(algorithm) => algorithm,
+                    None => {
+                        return Err(Error::UnknownPacker {
+                            path: path.to_path_buf(),
+                            name: name.clone(),
+                        })
+                    }
+                }
+            }
+        };
+        Ok(pack::PackOptions {
+            algorithm: algorithm,
+            max_size: self.max_size.unwrap_or(DEFAULT_MAX_ATLAS_SIZE),
+            power_of_two: self.power_of_two.unwrap_or(true),
+        })
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -58,185 +169,790 @@ pub struct TileSetSourceGroup {
 pub struct TileSetSourceItem {
     pub id: String,
     pub loc: [usize; 2],
+    /// How long `loc` is shown, required once the item has more frames.
+    #[serde(default)]
+    pub duration_ms: Option<u32>,
+    /// Animation frames shown after `loc`, in order, looping.
+    #[serde(default)]
+    pub frames: Vec<TileSetSourceFrame>,
+    /// Overrides the input format's properties for all of the item's parts.
+    #[serde(default)]
+    pub properties: TileProperties,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TileSetSourceFrame {
+    /// Takes the frame from another tile source laid out like the group's own.
+    #[serde(default)]
+    pub from: Option<String>,
+    pub loc: [usize; 2],
+    pub duration_ms: u32,
 }
 
 pub fn num_tiles(fmt: &OutputTileFormat) -> usize {
-    fmt.values().fold(0, |x, y| x + y)
+    fmt.parts.values().fold(0, |x, y| x + y)
+}
+
+#[derive(Clone, Debug)]
+pub struct CompileStats {
+    pub pages: Vec<[usize; 2]>,
+    /// Every tile referenced by the source, duplicates included.
+    pub tiles: usize,
+    /// The tiles actually written to the atlas.
+    pub unique_tiles: usize,
+    /// Atlas bytes (at 4 bytes per pixel) saved by sharing duplicate tiles.
+    pub bytes_saved: usize,
+    pub utilization: f64,
+    /// Every file written, the tile set and its pages.
+    pub outputs: Vec<PathBuf>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TileSet {
     pub tile_size: [usize; 2],
-    pub image_path: String,
+    /// One image per atlas page, relative to the tile set itself.
+    pub image_paths: Vec<String>,
     pub fmts: HashMap<String, TileSetItems>,
+    /// Autotile rules of the formats that have them.
+    #[serde(default)]
+    pub autotile: HashMap<String, autotile::AutotileTable>,
 }
 
-pub type TileSetItems = HashMap<String, Vec<[usize; 2]>>;
+pub type TileSetItems = HashMap<String, TileSetItem>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TileRect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
 
-#[derive(Debug)]
-pub enum Error {
-    Msg(&'static str),
-    ImageError(image::ImageError),
-    JsonError(serde_json::error::Error),
-    IOError(std::io::Error),
+/// A tile's rect within one page of the atlas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AtlasRect {
+    pub page: usize,
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
 }
 
-impl From<image::ImageError> for Error {
-    fn from(err: image::ImageError) -> Self {
-        Error::ImageError(err)
+impl AtlasRect {
+    pub fn new(page: usize, rect: &TileRect) -> AtlasRect {
+        AtlasRect {
+            page: page,
+            x: rect.x,
+            y: rect.y,
+            w: rect.w,
+            h: rect.h,
+        }
     }
 }
 
-impl From<serde_json::error::Error> for Error {
-    fn from(err: serde_json::error::Error) -> Self {
-        Error::JsonError(err)
+/// The atlas rects of one item, grouped by part in `OutputTileFormat` order.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TileSetItem {
+    /// The parts of the first frame.
+    pub parts: Vec<TileSetPart>,
+    /// Every frame of an animated item, the first one included, and empty
+    /// for items that aren't animated.
+    #[serde(default)]
+    pub frames: Vec<TileSetFrame>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TileSetFrame {
+    pub duration_ms: u32,
+    pub parts: Vec<TileSetPart>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TileSetPart {
+    pub name: String,
+    pub rects: Vec<AtlasRect>,
+    #[serde(default)]
+    pub properties: TileProperties,
+}
+
+impl TileSetItem {
+    pub fn part(&self, name: &str) -> Option<&TileSetPart> {
+        self.parts.iter().find(|p| p.name == name)
+    }
+
+    pub fn tile(&self, part: &str, index: usize) -> Option<&AtlasRect> {
+        self.part(part).and_then(|p| p.rects.get(index))
+    }
+
+    /// The frame showing `time_ms` into the item's animation, always 0 for
+    /// items that aren't animated.
+    pub fn frame_at(&self, time_ms: u64) -> usize {
+        frame_at(self.frames.iter().map(|f| f.duration_ms), time_ms)
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Error::IOError(err)
+// animations loop, so only the time into the current loop matters
+fn frame_at<I>(durations: I, time_ms: u64) -> usize
+    where I: Iterator<Item = u32> + Clone
+{
+    let period = durations.clone().fold(0, |sum, d| sum + d as u64);
+    if period == 0 {
+        return 0;
     }
+    let mut t = time_ms % period;
+    for (i, d) in durations.enumerate() {
+        if t < d as u64 {
+            return i;
+        }
+        t -= d as u64;
+    }
+    0
 }
 
-pub type TileSetResult<T> = Result<T, Error>;
+fn json_path(mut path: PathBuf) -> PathBuf {
+    path.set_extension("json");
+    path
+}
 
-struct TileSetCursor {
-    img: DynamicImage,
-    loc: [usize; 2],
-    tile_size: [usize; 2],
+fn load<T: serde::Deserialize>(path: PathBuf) -> TileSetResult<T> {
+    let path = json_path(path);
+    let reader = try!(File::open(&path).map_err(|err| Error::IOError(path.clone(), err)));
+    serde_json::de::from_reader(reader).map_err(|err| Error::JsonError(path, err))
 }
 
-impl TileSetCursor {
-    fn new(dimensions: [usize; 2], tile_size: [usize; 2]) -> TileSetCursor {
-        TileSetCursor {
-            img: DynamicImage::new_rgba8(dimensions[0] as u32, dimensions[1] as u32),
-            loc: [0, 0],
-            tile_size: tile_size,
+fn check_formats(ifmt_path: &Path,
+                 ifmt: &InputTileFormat,
+                 ofmt: &OutputTileFormat)
+                 -> TileSetResult<()> {
+    for (part, num) in &ofmt.parts {
+        let found = match (ifmt.parts.get(part), ifmt.composed.get(part)) {
+            (Some(tiles), None) => tiles.len(),
+            (None, Some(tiles)) => tiles.len(),
+            (Some(_), Some(_)) => {
+                return Err(Error::PartDefinedTwice {
+                    path: ifmt_path.to_path_buf(),
+                    fmt: ifmt.fmt.clone(),
+                    part: part.clone(),
+                })
+            }
+            (None, None) => {
+                return Err(Error::MissingPart {
+                    path: ifmt_path.to_path_buf(),
+                    fmt: ifmt.fmt.clone(),
+                    part: part.clone(),
+                })
+            }
+        };
+        if found != *num {
+            return Err(Error::TileCountMismatch {
+                path: ifmt_path.to_path_buf(),
+                fmt: ifmt.fmt.clone(),
+                part: part.clone(),
+                expected: *num,
+                found: found,
+            });
         }
     }
 
-    fn add_tile(&mut self,
-                from: &mut DynamicImage,
-                tile_coordinates: [usize; 2])
-                -> TileSetResult<[usize; 2]> {
-        let width = self.img.dimensions().0 as usize;
-        let x = tile_coordinates[0] * self.tile_size[0];
-        let y = tile_coordinates[1] * self.tile_size[1];
-        let sub = from.sub_image(x as u32, y as u32, self.tile_size[0] as u32, self.tile_size[1] as u32);
+    for part in ifmt.parts.keys().chain(ifmt.composed.keys()) {
+        if !ofmt.parts.contains_key(part) {
+            return Err(Error::UnexpectedPart {
+                path: ifmt_path.to_path_buf(),
+                fmt: ifmt.fmt.clone(),
+                part: part.clone(),
+            });
+        }
+    }
 
-        let ok = self.img.copy_from(&sub, self.loc[0] as u32, self.loc[1] as u32);
+    Ok(())
+}
 
-        self.loc[0] += self.tile_size[0];
-        if self.loc[0] + self.tile_size[0] > width {
-            self.loc[0] = 0;
-            self.loc[1] += self.tile_size[1];
-        }
+fn rect_in_bounds(rect: &TileRect, image_size: [usize; 2]) -> bool {
+    rect.x.checked_add(rect.w).map_or(false, |end| end <= image_size[0]) &&
+    rect.y.checked_add(rect.h).map_or(false, |end| end <= image_size[1])
+}
+
+// a rect of a source image, and where it goes within the output tile
+#[derive(Clone, Copy)]
+struct Piece {
+    src: TileRect,
+    offset: [usize; 2],
+}
+
+// where the tiles of one part come from
+enum PartTiles<'a> {
+    Whole(&'a [[usize; 2]]),
+    Composed(&'a [[[usize; 2]; 4]]),
+}
 
-        match ok {
-            true => Ok([self.loc[0], self.loc[1]]),
-            false => {
-                Err(Error::Msg("couldn't fit tile into image"))
+impl<'a> PartTiles<'a> {
+    // the source pieces of every tile of the part, for an item at `loc`,
+    // coordinates saturate so out of bounds tiles can still be reported
+    fn pieces(&self, loc: [usize; 2], tile_size: [usize; 2]) -> Vec<Vec<Piece>> {
+        match *self {
+            PartTiles::Whole(tiles) => {
+                tiles.iter()
+                    .map(|tile| {
+                        vec![Piece {
+                                 src: TileRect {
+                                     x: loc[0].saturating_add(tile[0]).saturating_mul(tile_size[0]),
+                                     y: loc[1].saturating_add(tile[1]).saturating_mul(tile_size[1]),
+                                     w: tile_size[0],
+                                     h: tile_size[1],
+                                 },
+                                 offset: [0, 0],
+                             }]
+                    })
+                    .collect()
+            }
+            PartTiles::Composed(tiles) => {
+                let half = [tile_size[0] / 2, tile_size[1] / 2];
+                tiles.iter()
+                    .map(|quarters| {
+                        quarters.iter()
+                            .enumerate()
+                            .map(|(i, q)| {
+                                Piece {
+                                    src: TileRect {
+                                        x: loc[0].saturating_mul(2).saturating_add(q[0]).saturating_mul(half[0]),
+                                        y: loc[1].saturating_mul(2).saturating_add(q[1]).saturating_mul(half[1]),
+                                        w: half[0],
+                                        h: half[1],
+                                    },
+                                    offset: [(i % 2) * half[0], (i / 2) * half[1]],
+                                }
+                            })
+                            .collect()
+                    })
+                    .collect()
             }
         }
+    }
+}
 
+// a tile source's image
+struct SourceImage {
+    from: String,
+    path: PathBuf,
+    img: RgbaImage,
+}
+
+impl SourceImage {
+    fn size(&self) -> [usize; 2] {
+        let (w, h) = self.img.dimensions();
+        [w as usize, h as usize]
     }
 }
 
-fn load<T: serde::Deserialize>(mut path: PathBuf) -> TileSetResult<T> {
-    path.set_extension("json");
-    let reader = try!(File::open(path));
-    let t: T = try!(serde_json::de::from_reader(reader));
-    Ok(t)
+struct LoadedGroup<'a> {
+    group: &'a TileSetSourceGroup,
+    ifmt: InputTileFormat,
+    ofmt: OutputTileFormat,
+    rules: Option<autotile::AutotileRules>,
+    // the group's own tile source first, then any others its frames use
+    images: Vec<SourceImage>,
 }
 
-pub fn compile_tile_set(src_folder: &Path,
-                        tile_set_source_path: &Path,
-                        target: &Path,
-                        tile_set_target_path: &Path) -> TileSetResult<()> {
-    let tss: TileSetSource = try!(load(src_folder.join(tile_set_source_path)));
-    let mut fmts = HashMap::<String, TileSetItems>::new();
-    let mut total_tiles = 0;
+impl<'a> LoadedGroup<'a> {
+    // the image and location of every frame of an item, its own loc first
+    fn frames(&self, item: &TileSetSourceItem) -> Vec<(usize, [usize; 2])> {
+        let mut frames = vec![(0, item.loc)];
+        for frame in &item.frames {
+            let image = frame.from
+                .as_ref()
+                .and_then(|from| self.images.iter().position(|i| &i.from == from))
+                .unwrap_or(0);
+            frames.push((image, frame.loc));
+        }
+        frames
+    }
 
-    for group in &tss.groups {
-        let from: TileSource = try!(load(src_folder.join(&group.from)));
-        let ifmt: InputTileFormat = try!(load(src_folder.join(&group.fmt)));
-        let ofmt: OutputTileFormat = try!(load(src_folder.join(&ifmt.fmt)));
+    // the parts of the input format, in output format order
+    fn parts(&self) -> Vec<(&String, PartTiles)> {
+        self.ofmt
+            .parts
+            .keys()
+            .filter_map(|k| {
+                match (self.ifmt.parts.get(k), self.ifmt.composed.get(k)) {
+                    (Some(tiles), _) => Some((k, PartTiles::Whole(tiles))),
+                    (_, Some(tiles)) => Some((k, PartTiles::Composed(tiles))),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+fn load_image(src_folder: &Path,
+              tss_path: &Path,
+              tss: &TileSetSource,
+              from: &str)
+              -> TileSetResult<SourceImage> {
+    let source: TileSource = try!(load(src_folder.join(from)));
+    if tss.tile_size != source.tile_size {
+        return Err(Error::TileSizeMismatch {
+            path: tss_path.to_path_buf(),
+            from: from.to_string(),
+            expected: tss.tile_size,
+            found: source.tile_size,
+        });
+    }
+
+    let path = src_folder.join(&source.image_path);
+    let img = try!(image::open(&path).map_err(|err| Error::ImageError(path.clone(), err)));
+    Ok(SourceImage {
+        from: from.to_string(),
+        path: path,
+        img: img.to_rgba(),
+    })
+}
+
+fn load_group<'a>(src_folder: &Path,
+                  tss_path: &Path,
+                  tss: &TileSetSource,
+                  group: &'a TileSetSourceGroup)
+                  -> TileSetResult<LoadedGroup<'a>> {
+    let mut images = vec![try!(load_image(src_folder, tss_path, tss, &group.from))];
+    let ifmt_path = json_path(src_folder.join(&group.fmt));
+    let ifmt: InputTileFormat = try!(load(ifmt_path.clone()));
+    let ofmt_path = json_path(src_folder.join(&ifmt.fmt));
+    let ofmt: OutputTileFormat = try!(load(ofmt_path.clone()));
 
-        if tss.tile_size != from.tile_size {
-            return Err(Error::Msg("inconsistent tile size"));
+    try!(check_formats(&ifmt_path, &ifmt, &ofmt));
+    let mut errors = Vec::new();
+    for (part, props) in &ifmt.properties {
+        if !ofmt.parts.contains_key(part) {
+            return Err(Error::UnexpectedPart {
+                path: ifmt_path,
+                fmt: ifmt.fmt.clone(),
+                part: part.clone(),
+            });
         }
+        errors.extend(props.validate(&ifmt_path, part, tss.tile_size));
+    }
+    if !errors.is_empty() {
+        return Err(Error::Invalid(errors));
+    }
+    if !ifmt.composed.is_empty() && (tss.tile_size[0] % 2 != 0 || tss.tile_size[1] % 2 != 0) {
+        return Err(Error::OddTileSize {
+            path: ifmt_path,
+            tile_size: tss.tile_size,
+        });
+    }
 
-        for (part, num) in &ofmt {
-            if ifmt.parts[part].len() != *num {
-                return Err(Error::Msg("input & output format don't match"));
+    let rules = match ofmt.autotile {
+        Some(ref source) => Some(try!(autotile::AutotileRules::from_source(&ofmt_path, &ofmt, source))),
+        None => None,
+    };
+
+    for item in &group.items {
+        for frame in &item.frames {
+            if let Some(ref from) = frame.from {
+                if !images.iter().any(|i| &i.from == from) {
+                    images.push(try!(load_image(src_folder, tss_path, tss, from)));
+                }
             }
         }
+    }
 
-        total_tiles += num_tiles(&ofmt) * group.items.len();
+    Ok(LoadedGroup {
+        group: group,
+        ifmt: ifmt,
+        ofmt: ofmt,
+        rules: rules,
+        images: images,
+    })
+}
+
+// every tile rectangle referenced by the tile set source must lie inside
+// of its source image, checked up front so all mistakes are reported at once
+fn validate(tss_path: &Path, tss: &TileSetSource, groups: &[LoadedGroup]) -> Vec<Error> {
+    let mut errors = Vec::new();
+    for g in groups {
+        for item in &g.group.items {
+            errors.extend(item.properties.validate(tss_path, &item.id, tss.tile_size));
+            if !item.frames.is_empty() {
+                let durations = iter::once(item.duration_ms)
+                    .chain(item.frames.iter().map(|f| Some(f.duration_ms)));
+                for (frame, duration) in durations.enumerate() {
+                    if duration.map_or(true, |d| d == 0) {
+                        errors.push(Error::BadFrameDuration {
+                            path: tss_path.to_path_buf(),
+                            id: item.id.clone(),
+                            frame: frame,
+                        });
+                    }
+                }
+            }
+
+            for (image, loc) in g.frames(item) {
+                let image = &g.images[image];
+                let image_size = image.size();
+                for (part, tiles) in g.parts() {
+                    for pieces in tiles.pieces(loc, tss.tile_size) {
+                        // one error per tile, pointing at its first bad piece
+                        if let Some(piece) = pieces.iter().find(|p| !rect_in_bounds(&p.src, image_size)) {
+                            errors.push(Error::SourceTileOutOfBounds {
+                                path: tss_path.to_path_buf(),
+                                image: image.path.clone(),
+                                id: item.id.clone(),
+                                part: part.clone(),
+                                tile: [piece.src.x / tss.tile_size[0], piece.src.y / tss.tile_size[1]],
+                                image_size: image_size,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+    errors
+}
+
+// the distinct tiles referenced by a tile set, tiles with identical pixels
+// are only kept once no matter how many items refer to them
+struct SourceTiles {
+    tiles: Vec<SourceTile>,
+    pixels: Vec<Vec<u8>>,
+    by_hash: HashMap<u64, Vec<usize>>,
+    references: usize,
+    bytes_saved: usize,
+}
+
+// a tile made of pieces of one of its group's images
+struct SourceTile {
+    group: usize,
+    image: usize,
+    size: [usize; 2],
+    pieces: Vec<Piece>,
+}
+
+impl SourceTiles {
+    fn new() -> SourceTiles {
+        SourceTiles {
+            tiles: Vec::new(),
+            pixels: Vec::new(),
+            by_hash: HashMap::new(),
+            references: 0,
+            bytes_saved: 0,
+        }
     }
 
-    let root = (total_tiles as f64).sqrt().floor() as usize + 1;
-    let dimensions = [root * tss.tile_size[0], root * tss.tile_size[1]];
-    let mut cursor = TileSetCursor::new(dimensions, tss.tile_size);
+    fn add(&mut self, groups: &[LoadedGroup], tile: SourceTile) -> usize {
+        let pixels = tile_pixels(&groups[tile.group].images[tile.image].img, tile.size, &tile.pieces);
+        let mut hasher = hash::Fnv64::new();
+        hasher.write_usize(tile.size[0]);
+        hasher.write_usize(tile.size[1]);
+        hasher.write(&pixels);
+        let key = hasher.finish();
+
+        self.references += 1;
+        let existing = self.by_hash
+            .get(&key)
+            .and_then(|candidates| candidates.iter().cloned().find(|&i| self.pixels[i] == pixels));
+
+        match existing {
+            Some(i) => {
+                self.bytes_saved += pixels.len();
+                i
+            }
+            None => {
+                let i = self.tiles.len();
+                self.tiles.push(tile);
+                self.pixels.push(pixels);
+                self.by_hash.entry(key).or_insert(Vec::new()).push(i);
+                i
+            }
+        }
+    }
+}
+
+fn tile_pixels(img: &RgbaImage, size: [usize; 2], pieces: &[Piece]) -> Vec<u8> {
+    let mut pixels = vec![0; size[0] * size[1] * 4];
+    for piece in pieces {
+        for y in 0..piece.src.h {
+            for x in 0..piece.src.w {
+                let i = ((piece.offset[1] + y) * size[0] + piece.offset[0] + x) * 4;
+                let pixel = img.get_pixel((piece.src.x + x) as u32, (piece.src.y + y) as u32);
+                pixels[i..i + 4].copy_from_slice(&pixel.data);
+            }
+        }
+    }
+    pixels
+}
+
+struct PendingItem {
+    fmt: String,
+    id: String,
+    // the duration of every frame, with its part names and indices into
+    // the list of source tiles
+    frames: Vec<(u32, Vec<(String, Vec<usize>)>)>,
+    // the merged properties of every part
+    properties: Vec<TileProperties>,
+}
 
+// a compiled tile set that hasn't been written out yet
+struct Compiled {
+    tile_size: [usize; 2],
+    pages: Vec<DynamicImage>,
+    fmts: HashMap<String, TileSetItems>,
+    autotile: HashMap<String, autotile::AutotileTable>,
+    stats: CompileStats,
+}
+
+fn compile(src_folder: &Path, tile_set_source_path: &Path) -> TileSetResult<Compiled> {
+    let tss_path = json_path(src_folder.join(tile_set_source_path));
+    let tss: TileSetSource = try!(load(tss_path.clone()));
+    let opts = try!(tss.atlas.pack_options(&tss_path));
+
+    let mut groups = Vec::with_capacity(tss.groups.len());
     for group in &tss.groups {
-        let from: TileSource = try!(load(src_folder.join(&group.from)));
-        let ifmt: InputTileFormat = try!(load(src_folder.join(&group.fmt)));
+        groups.push(try!(load_group(src_folder, &tss_path, &tss, group)));
+    }
+
+    let errors = validate(&tss_path, &tss, &groups);
+    if !errors.is_empty() {
+        return Err(Error::Invalid(errors));
+    }
 
-        let mut src_img = try!(image::open(src_folder.join(&from.image_path)));
+    let mut sources = SourceTiles::new();
+    let mut pending = Vec::<PendingItem>::new();
+    let mut seen = HashSet::<(String, String)>::new();
 
-        for item in &group.items {
-            let (x, y) = (item.loc[0], item.loc[1]);
-            let mut out_pxs = Vec::<[usize; 2]>::new();
-            for tile in ifmt.parts.values().flat_map(|c| c.iter()) {
-                let px = try!(cursor.add_tile(&mut src_img, [x + tile[0], y + tile[1]]));
-                out_pxs.push(px);
+    for (gi, g) in groups.iter().enumerate() {
+        for item in &g.group.items {
+            if !seen.insert((g.ifmt.fmt.clone(), item.id.clone())) {
+                return Err(Error::DuplicateId {
+                    path: tss_path.clone(),
+                    fmt: g.ifmt.fmt.clone(),
+                    id: item.id.clone(),
+                });
             }
 
-            let mut m = fmts.entry(ifmt.fmt.clone()).or_insert(HashMap::new());
-            match m.insert(item.id.clone(), out_pxs) {
-                Some(_) => return Err(Error::Msg("duplicate item")),
-                _ => { }
-            };
+            let durations = iter::once(item.duration_ms.unwrap_or(0))
+                .chain(item.frames.iter().map(|f| f.duration_ms));
+            let mut frames = Vec::new();
+            for ((image, loc), duration) in g.frames(item).into_iter().zip(durations) {
+                let mut parts = Vec::new();
+                for (part, tiles) in g.parts() {
+                    let indices = tiles.pieces(loc, tss.tile_size)
+                        .into_iter()
+                        .map(|pieces| {
+                            sources.add(&groups,
+                                        SourceTile {
+                                            group: gi,
+                                            image: image,
+                                            size: tss.tile_size,
+                                            pieces: pieces,
+                                        })
+                        })
+                        .collect();
+                    parts.push((part.clone(), indices));
+                }
+                frames.push((duration, parts));
+            }
+
+            let properties = g.parts()
+                .iter()
+                .map(|&(part, _)| {
+                    g.ifmt
+                        .properties
+                        .get(part)
+                        .map_or(item.properties.clone(), |p| p.merged(&item.properties))
+                })
+                .collect();
+
+            pending.push(PendingItem {
+                fmt: g.ifmt.fmt.clone(),
+                id: item.id.clone(),
+                frames: frames,
+                properties: properties,
+            });
+        }
+    }
+
+    let sizes: Vec<[usize; 2]> = sources.tiles.iter().map(|t| t.size).collect();
+    let packing = match pack::pack_pages(&sizes, &opts) {
+        Some(packing) => packing,
+        None => {
+            return Err(Error::AtlasOverflow {
+                path: tss_path.clone(),
+                max_size: opts.max_size,
+            })
+        }
+    };
+
+    let mut pages: Vec<DynamicImage> = packing.pages
+        .iter()
+        .map(|size| DynamicImage::new_rgba8(size[0] as u32, size[1] as u32))
+        .collect();
+    for (tile, dst) in sources.tiles.iter().zip(&packing.placements) {
+        for piece in &tile.pieces {
+            let src = piece.src;
+            let img = &mut groups[tile.group].images[tile.image].img;
+            let sub = img.sub_image(src.x as u32, src.y as u32, src.w as u32, src.h as u32);
+            pages[dst.page].copy_from(&sub,
+                                      (dst.rect.x + piece.offset[0]) as u32,
+                                      (dst.rect.y + piece.offset[1]) as u32);
         }
     }
 
-    //TODO: FIXXXXXX
-    let img_path = {
-        let mut p = target.join(tile_set_target_path);
-        p.set_extension("png");
-        p
+    let to_parts = |parts: Vec<(String, Vec<usize>)>, properties: &[TileProperties]| -> Vec<TileSetPart> {
+        parts.into_iter()
+            .zip(properties)
+            .map(|((name, tiles), props)| {
+                TileSetPart {
+                    name: name,
+                    rects: tiles.iter()
+                        .map(|&t| {
+                            let p = &packing.placements[t];
+                            AtlasRect::new(p.page, &p.rect)
+                        })
+                        .collect(),
+                    properties: props.clone(),
+                }
+            })
+            .collect()
     };
 
-    let ts_path = {
-        let mut p = target.join(tile_set_target_path);
-        p.set_extension("json");
-        p
+    let mut fmts = HashMap::<String, TileSetItems>::new();
+    for item in pending {
+        let properties = item.properties;
+        let mut frames: Vec<TileSetFrame> = item.frames
+            .into_iter()
+            .map(|(duration, parts)| {
+                TileSetFrame {
+                    duration_ms: duration,
+                    parts: to_parts(parts, &properties[..]),
+                }
+            })
+            .collect();
+        let parts = frames[0].parts.clone();
+        if frames.len() == 1 {
+            frames.clear();
+        }
+        fmts.entry(item.fmt)
+            .or_insert(HashMap::new())
+            .insert(item.id,
+                    TileSetItem {
+                        parts: parts,
+                        frames: frames,
+                    });
+    }
+
+    let mut tables = HashMap::new();
+    for g in &groups {
+        if let Some(ref rules) = g.rules {
+            tables.insert(g.ifmt.fmt.clone(), rules.to_table(&g.ofmt));
+        }
+    }
+
+    let stats = CompileStats {
+        pages: packing.pages.clone(),
+        tiles: sources.references,
+        unique_tiles: sources.tiles.len(),
+        bytes_saved: sources.bytes_saved,
+        utilization: packing.utilization(),
+        outputs: Vec::new(),
     };
 
-    let ts = TileSet {
+    Ok(Compiled {
         tile_size: tss.tile_size,
-        image_path: String::from(img_path.to_str().unwrap()),
+        pages: pages,
         fmts: fmts,
-    };
+        autotile: tables,
+        stats: stats,
+    })
+}
 
-    {
-        let mut writer = try!(File::create(ts_path));
-        try!(serde_json::ser::to_writer(&mut writer, &ts));
+/// Runs the compiler without writing anything, for checking a tile set source.
+pub fn check_tile_set(src_folder: &Path, tile_set_source_path: &Path) -> TileSetResult<CompileStats> {
+    compile(src_folder, tile_set_source_path).map(|compiled| compiled.stats)
+}
+
+pub fn compile_tile_set(src_folder: &Path,
+                        tile_set_source_path: &Path,
+                        target: &Path,
+                        tile_set_target_path: &Path) -> TileSetResult<CompileStats> {
+    let Compiled { tile_size, pages, fmts, autotile, mut stats } =
+        try!(compile(src_folder, tile_set_source_path));
+
+    let ts_path = json_path(target.join(tile_set_target_path));
+    let name = ts_path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or(String::from("tile_set"));
+    let image_paths: Vec<String> = (0..pages.len())
+        .map(|page| format!("{}.{}.png", name, page))
+        .collect();
+
+    if let Some(dir) = ts_path.parent() {
+        try!(fs::create_dir_all(dir).map_err(|err| Error::IOError(dir.to_path_buf(), err)));
+    }
+
+    let mut outputs = Vec::with_capacity(pages.len() + 1);
+    for (page, file_name) in pages.iter().zip(&image_paths) {
+        let img_path = ts_path.with_file_name(file_name);
+        outputs.push(img_path.clone());
+        let mut writer = try!(File::create(&img_path)
+            .map_err(|err| Error::IOError(img_path.clone(), err)));
+        try!(page.save(&mut writer, ImageFormat::PNG)
+            .map_err(|err| Error::ImageError(img_path.clone(), err)));
     }
 
+    let ts = TileSet {
+        tile_size: tile_size,
+        image_paths: image_paths,
+        fmts: fmts,
+        autotile: autotile,
+    };
+
     {
-        let mut writer = try!(File::create(img_path));
-        try!(cursor.img.save(&mut writer, ImageFormat::PNG));
+        let mut writer = try!(File::create(&ts_path)
+            .map_err(|err| Error::IOError(ts_path.clone(), err)));
+        try!(serde_json::ser::to_writer(&mut writer, &ts)
+            .map_err(|err| Error::JsonError(ts_path.clone(), err)));
     }
+    outputs.push(ts_path);
 
-    Ok(())
+    stats.outputs = outputs;
+    Ok(stats)
+}
+
+/// Where a tile set source's compiled output goes, relative to the target
+/// folder: `tile_set_sources/x` becomes `tile_sets/x`.
+pub fn tile_set_target_path(tile_set_source_path: &Path) -> PathBuf {
+    let mut components = tile_set_source_path.components();
+    let path = match components.next() {
+        Some(first) if first.as_os_str() == "tile_set_sources" => {
+            Path::new("tile_sets").join(components.as_path())
+        }
+        _ => tile_set_source_path.to_path_buf(),
+    };
+    path.with_extension("")
+}
+
+/// Every tile set source in `src_folder/tile_set_sources`, relative to `src_folder`.
+pub fn find_tile_set_sources(src_folder: &Path) -> TileSetResult<Vec<PathBuf>> {
+    let dir = src_folder.join("tile_set_sources");
+    let entries = try!(fs::read_dir(&dir).map_err(|err| Error::IOError(dir.clone(), err)));
+
+    let mut sources = Vec::new();
+    for entry in entries {
+        let path = try!(entry.map_err(|err| Error::IOError(dir.clone(), err))).path();
+        if path.extension().map_or(false, |ext| ext == "json") {
+            if let Some(stem) = path.file_stem() {
+                sources.push(Path::new("tile_set_sources").join(stem));
+            }
+        }
+    }
+    sources.sort();
+    Ok(sources)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::{check_formats, load, load_group, validate};
     use std::path::Path;
 
     #[test]
@@ -246,4 +962,234 @@ mod tests {
                          &Path::new("test_data/target"),
                          &Path::new("tile_sets/morning")).expect("compilation failed");
     }
+
+    #[test]
+    fn parts_follow_output_format() {
+        compile_tile_set(&Path::new("test_data/src"),
+                         &Path::new("tile_set_sources/morning"),
+                         &Path::new("test_data/target"),
+                         &Path::new("tile_sets/morning_parts")).expect("compilation failed");
+        let ts: TileSet = load(Path::new("test_data/target/tile_sets/morning_parts").to_path_buf())
+            .expect("couldn't read compiled tile set");
+
+        let grass = &ts.fmts["output_tile_formats/floor"]["morning_grass"];
+        let names: Vec<&str> = grass.parts.iter().map(|p| &p.name[..]).collect();
+        assert_eq!(names, vec!["closed_center", "left_right", "numpad", "top_bottom"]);
+        assert_eq!(grass.part("numpad").unwrap().rects.len(), 9);
+        assert!(grass.tile("numpad", 2).is_some());
+        assert!(grass.tile("numpad", 9).is_none());
+    }
+
+    #[test]
+    fn sources_map_onto_targets() {
+        assert_eq!(find_tile_set_sources(Path::new("test_data/src")).expect("couldn't list sources"),
+                   vec![Path::new("tile_set_sources/blob").to_path_buf(),
+                        Path::new("tile_set_sources/morning").to_path_buf()]);
+        assert_eq!(tile_set_target_path(Path::new("tile_set_sources/morning.json")),
+                   Path::new("tile_sets/morning").to_path_buf());
+        assert_eq!(tile_set_target_path(Path::new("other/morning")),
+                   Path::new("other/morning").to_path_buf());
+    }
+
+    #[test]
+    fn atlas_is_tightly_packed() {
+        let stats = compile_tile_set(&Path::new("test_data/src"),
+                                     &Path::new("tile_set_sources/morning"),
+                                     &Path::new("test_data/target"),
+                                     &Path::new("tile_sets/morning_packed"))
+            .expect("compilation failed");
+        assert_eq!(stats.tiles, 4 * 16 + 4 * 13);
+        assert_eq!(stats.unique_tiles, 112);
+        assert_eq!(stats.bytes_saved, 4 * 16 * 16 * 4);
+        assert_eq!(stats.pages.len(), 1);
+        assert_eq!(stats.pages[0][0] * stats.pages[0][1], 256 * 128);
+        assert!(stats.utilization > 0.85);
+    }
+
+    #[test]
+    fn small_max_size_spills_into_pages() {
+        let src = Path::new("test_data/src");
+        let mut tss: TileSetSource = load(src.join("tile_set_sources/morning"))
+            .expect("couldn't read tile set source");
+        tss.atlas.max_size = Some([64, 64]);
+        {
+            let path = Path::new("test_data/target/tile_set_sources/paged.json");
+            fs::create_dir_all(path.parent().unwrap()).expect("couldn't create folder");
+            let mut writer = File::create(path).expect("couldn't create source");
+            serde_json::ser::to_writer(&mut writer, &tss).expect("couldn't write source");
+        }
+
+        // the modified source is written out next to the build output, its
+        // groups still resolve against the regular source folder
+        let stats = compile_tile_set(&Path::new("test_data/src"),
+                                     &Path::new("../target/tile_set_sources/paged"),
+                                     &Path::new("test_data/target"),
+                                     &Path::new("tile_sets/paged"))
+            .expect("compilation failed");
+        assert_eq!(stats.pages.len(), 7);
+
+        let ts: TileSet = load(Path::new("test_data/target/tile_sets/paged").to_path_buf())
+            .expect("couldn't read compiled tile set");
+        assert_eq!(ts.image_paths.len(), 7);
+        assert_eq!(ts.image_paths[6], "paged.6.png");
+        let wall = &ts.fmts["output_tile_formats/wall"]["morning_stone_wall"];
+        assert!(wall.parts.iter().flat_map(|p| p.rects.iter()).all(|r| r.page < 7));
+    }
+
+    #[test]
+    fn quarters_compose_blob_tiles() {
+        let stats = compile_tile_set(&Path::new("test_data/src"),
+                                     &Path::new("tile_set_sources/blob"),
+                                     &Path::new("test_data/target"),
+                                     &Path::new("tile_sets/blob"))
+            .expect("compilation failed");
+        // blue water's second frame is the purple water
+        assert_eq!(stats.tiles, 3 * 47);
+        assert_eq!(stats.unique_tiles, 2 * 47);
+
+        let ts = LoadedTileSet::open(Path::new("test_data/target/tile_sets/blob.json"))
+            .expect("couldn't load compiled tile set");
+        let fmt = ts.format("output_tile_formats/blob47").expect("missing format");
+        let water = ts.tile(fmt, "blue_water").expect("missing item");
+        let table = ts.autotile(fmt).expect("missing autotile table");
+
+        // every quarter of the source sheet has its own colour
+        let quarter = |mask: u8, i: u32| {
+            let tile = table.resolve(mask);
+            let rect = ts.rect(water, tile[0], tile[1]).expect("missing tile");
+            let x = rect.x as u32 + (i % 2) * 8 + 4;
+            let y = rect.y as u32 + (i / 2) * 8 + 4;
+            let p = ts.pages[rect.page].get_pixel(x, y).data;
+            [p[0] as usize / 60, p[1] as usize / 40]
+        };
+        let isolated: Vec<_> = (0..4).map(|i| quarter(0, i)).collect();
+        assert_eq!(isolated, vec![[0, 2], [3, 2], [0, 5], [3, 5]]);
+        let surrounded: Vec<_> = (0..4).map(|i| quarter(255, i)).collect();
+        assert_eq!(surrounded, vec![[2, 4], [1, 4], [2, 3], [1, 3]]);
+        let inner = autotile::NORTH | autotile::EAST | autotile::SOUTH | autotile::WEST;
+        let corners: Vec<_> = (0..4).map(|i| quarter(inner, i)).collect();
+        assert_eq!(corners, vec![[2, 0], [3, 0], [2, 1], [3, 1]]);
+    }
+
+    #[test]
+    fn animated_items_keep_their_frames() {
+        compile_tile_set(&Path::new("test_data/src"),
+                         &Path::new("tile_set_sources/blob"),
+                         &Path::new("test_data/target"),
+                         &Path::new("tile_sets/blob_frames"))
+            .expect("compilation failed");
+        let ts: TileSet = load(Path::new("test_data/target/tile_sets/blob_frames").to_path_buf())
+            .expect("couldn't read compiled tile set");
+
+        let items = &ts.fmts["output_tile_formats/blob47"];
+        let blue = &items["blue_water"];
+        let purple = &items["purple_water"];
+        assert!(purple.frames.is_empty());
+        assert_eq!(purple.frame_at(100), 0);
+
+        let durations: Vec<u32> = blue.frames.iter().map(|f| f.duration_ms).collect();
+        assert_eq!(durations, vec![250, 500]);
+        assert_eq!(blue.frames[0].parts[0].rects, blue.parts[0].rects);
+        assert_eq!(blue.frames[1].parts[0].rects, purple.parts[0].rects);
+        assert_eq!(blue.frame_at(260), 1);
+        assert_eq!(blue.frame_at(760), 0);
+    }
+
+    #[test]
+    fn frames_need_durations() {
+        let src = Path::new("test_data/src");
+        let mut tss: TileSetSource = load(src.join("tile_set_sources/blob"))
+            .expect("couldn't read tile set source");
+        tss.groups[0].items[0].duration_ms = None;
+        tss.groups[0].items[0].frames[0].duration_ms = 0;
+
+        let tss_path = Path::new("blob.json");
+        let groups: Vec<_> = tss.groups
+            .iter()
+            .map(|g| load_group(src, tss_path, &tss, g).expect("couldn't load group"))
+            .collect();
+
+        let frames: Vec<usize> = validate(tss_path, &tss, &groups)
+            .iter()
+            .filter_map(|e| match *e {
+                Error::BadFrameDuration { frame, .. } => Some(frame),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(frames, vec![0, 1]);
+    }
+
+    #[test]
+    fn properties_reach_the_tile_set() {
+        compile_tile_set(&Path::new("test_data/src"),
+                         &Path::new("tile_set_sources/morning"),
+                         &Path::new("test_data/target"),
+                         &Path::new("tile_sets/morning_properties")).expect("compilation failed");
+        let ts: TileSet = load(Path::new("test_data/target/tile_sets/morning_properties").to_path_buf())
+            .expect("couldn't read compiled tile set");
+
+        let walls = &ts.fmts["output_tile_formats/wall"];
+        let wood = &walls["morning_wood_wall"];
+        let props = &wood.part("cross").unwrap().properties;
+        assert_eq!(props.solid, Some(true));
+        assert_eq!(props.custom["flammable"], serde_json::value::Value::Bool(true));
+        assert!(walls["morning_brick_wall"].parts.iter().all(|p| p.properties.custom.is_empty()));
+
+        let grass = &ts.fmts["output_tile_formats/floor"]["morning_grass"];
+        assert!(grass.parts.iter().all(|p| p.properties.movement_cost == Some(1.5)));
+        assert_eq!(grass.part("numpad").unwrap().properties.solid, None);
+    }
+
+    #[test]
+    fn missing_part_is_reported() {
+        let mut ifmt: InputTileFormat = load(Path::new("test_data/src/input_tile_formats/dawnlike_floor").to_path_buf())
+            .expect("couldn't read input format");
+        let ofmt: OutputTileFormat = load(Path::new("test_data/src/output_tile_formats/floor").to_path_buf())
+            .expect("couldn't read output format");
+        ifmt.parts.remove("numpad");
+
+        match check_formats(Path::new("dawnlike_floor.json"), &ifmt, &ofmt) {
+            Err(Error::MissingPart { ref part, .. }) => assert_eq!(part, "numpad"),
+            other => panic!("expected a missing part error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bare_output_formats_still_load() {
+        let old: OutputTileFormat = serde_json::de::from_str(r#"{ "numpad": 9, "parts": 2 }"#)
+            .expect("couldn't read a bare output format");
+        assert_eq!(old.parts["numpad"], 9);
+        assert_eq!(old.parts["parts"], 2);
+        assert!(old.autotile.is_none());
+
+        let new: OutputTileFormat = serde_json::de::from_str(r#"{ "parts": { "numpad": 9 } }"#)
+            .expect("couldn't read an output format");
+        assert_eq!(new.parts.len(), 1);
+        assert!(serde_json::de::from_str::<OutputTileFormat>(r#"{ "numpad": "nine" }"#).is_err());
+    }
+
+    #[test]
+    fn out_of_bounds_tiles_are_all_reported() {
+        let src = Path::new("test_data/src");
+        let mut tss: TileSetSource = load(src.join("tile_set_sources/morning"))
+            .expect("couldn't read tile set source");
+        tss.groups[0].items[1].loc = [1000, 0];
+        tss.groups[1].items[2].loc = [0, 1000];
+
+        let tss_path = Path::new("morning.json");
+        let groups: Vec<_> = tss.groups
+            .iter()
+            .map(|g| load_group(src, tss_path, &tss, g).expect("couldn't load group"))
+            .collect();
+
+        let errors = validate(tss_path, &tss, &groups);
+        assert_eq!(errors.len(), 16 + 13);
+        let ids: Vec<String> = errors.iter()
+            .filter_map(|e| match *e {
+                Error::SourceTileOutOfBounds { ref id, .. } => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+        assert!(ids.iter().all(|id| id == "morning_brick" || id == "morning_wood_wall"));
+    }
 }
@@ -0,0 +1,372 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use image::{self, RgbaImage};
+use serde_json;
+
+use {frame_at, AtlasRect, Error, TileProperties, TileSet, TileSetPart, TileSetResult};
+use autotile::AutotileTable;
+
+/// An interned output format name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FormatId(pub u32);
+
+/// An interned tile set item.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TileId(pub u32);
+
+/// A rect in texture coordinates, with the origin at the top left of the page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UvRect {
+    pub page: usize,
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+struct FormatEntry {
+    name: String,
+    parts: Vec<String>,
+    tiles: HashMap<String, TileId>,
+    autotile: Option<AutotileTable>,
+}
+
+struct TileEntry {
+    format: FormatId,
+    id: String,
+    // for every frame, the start and length of every part within `rects`
+    // and `uvs`
+    frames: Vec<Vec<(usize, usize)>>,
+    // empty unless the tile is animated
+    durations: Vec<u32>,
+    // by part
+    properties: Vec<TileProperties>,
+}
+
+/// A compiled tile set ready for rendering. Every string lookup happens up
+/// front, after which tiles are addressed by handle and part index.
+pub struct LoadedTileSet {
+    pub tile_size: [usize; 2],
+    pub pages: Vec<RgbaImage>,
+    formats: Vec<FormatEntry>,
+    format_ids: HashMap<String, FormatId>,
+    tiles: Vec<TileEntry>,
+    rects: Vec<AtlasRect>,
+    uvs: Vec<UvRect>,
+}
+
+impl LoadedTileSet {
+    /// Loads a compiled tile set along with its pages, which are looked up
+    /// relative to the tile set.
+    pub fn open(path: &Path) -> TileSetResult<LoadedTileSet> {
+        let reader = try!(File::open(path).map_err(|err| Error::IOError(path.to_path_buf(), err)));
+        let ts: TileSet = try!(serde_json::de::from_reader(reader)
+            .map_err(|err| Error::JsonError(path.to_path_buf(), err)));
+
+        let mut pages = Vec::with_capacity(ts.image_paths.len());
+        for image_path in &ts.image_paths {
+            let page_path = path.with_file_name(image_path);
+            let page = try!(image::open(&page_path)
+                .map_err(|err| Error::ImageError(page_path.clone(), err)));
+            pages.push(page.to_rgba());
+        }
+
+        LoadedTileSet::new(path, ts, pages)
+    }
+
+    /// Checks every rect of `ts` against its page and builds the lookup
+    /// tables. `path` is only used for error reporting.
+    pub fn new(path: &Path, ts: TileSet, pages: Vec<RgbaImage>) -> TileSetResult<LoadedTileSet> {
+        let mut loaded = LoadedTileSet {
+            tile_size: ts.tile_size,
+            pages: pages,
+            formats: Vec::new(),
+            format_ids: HashMap::new(),
+            tiles: Vec::new(),
+            rects: Vec::new(),
+            uvs: Vec::new(),
+        };
+
+        // sorted, so handles come out the same every time a tile set is loaded
+        let mut fmt_names: Vec<&String> = ts.fmts.keys().collect();
+        fmt_names.sort();
+
+        for fmt_name in fmt_names {
+            let items = &ts.fmts[fmt_name];
+            let format = FormatId(loaded.formats.len() as u32);
+            let mut entry = FormatEntry {
+                name: fmt_name.clone(),
+                parts: Vec::new(),
+                tiles: HashMap::new(),
+                autotile: None,
+            };
+
+            let mut ids: Vec<&String> = items.keys().collect();
+            ids.sort();
+            for (i, id) in ids.into_iter().enumerate() {
+                let item = &items[id];
+                if i == 0 {
+                    entry.parts = item.parts.iter().map(|p| p.name.clone()).collect();
+                }
+
+                let frame_parts: Vec<&Vec<TileSetPart>> = if item.frames.is_empty() {
+                    vec![&item.parts]
+                } else {
+                    item.frames.iter().map(|f| &f.parts).collect()
+                };
+
+                let mut frames = Vec::with_capacity(frame_parts.len());
+                for item_parts in frame_parts {
+                    if !item_parts.iter().map(|p| &p.name).eq(entry.parts.iter()) {
+                        return Err(Error::InconsistentParts {
+                            path: path.to_path_buf(),
+                            fmt: fmt_name.clone(),
+                            id: id.clone(),
+                        });
+                    }
+
+                    let mut parts = Vec::with_capacity(item_parts.len());
+                    for part in item_parts {
+                        parts.push((loaded.rects.len(), part.rects.len()));
+                        for rect in &part.rects {
+                            let uv = match loaded.uv_rect(rect) {
+                                Some(uv) => uv,
+                                None => {
+                                    return Err(Error::AtlasRectOutOfBounds {
+                                        path: path.to_path_buf(),
+                                        fmt: fmt_name.clone(),
+                                        id: id.clone(),
+                                        part: part.name.clone(),
+                                        rect: *rect,
+                                    })
+                                }
+                            };
+                            loaded.rects.push(*rect);
+                            loaded.uvs.push(uv);
+                        }
+                    }
+                    frames.push(parts);
+                }
+
+                let tile = TileId(loaded.tiles.len() as u32);
+                loaded.tiles.push(TileEntry {
+                    format: format,
+                    id: id.clone(),
+                    frames: frames,
+                    durations: item.frames.iter().map(|f| f.duration_ms).collect(),
+                    properties: item.parts.iter().map(|p| p.properties.clone()).collect(),
+                });
+                entry.tiles.insert(id.clone(), tile);
+            }
+
+            if let Some(table) = ts.autotile.get(fmt_name) {
+                if !loaded.table_fits(table, &entry) {
+                    return Err(Error::BadAutotileTable {
+                        path: path.to_path_buf(),
+                        fmt: fmt_name.clone(),
+                    });
+                }
+                entry.autotile = Some(table.clone());
+            }
+
+            loaded.format_ids.insert(fmt_name.clone(), format);
+            loaded.formats.push(entry);
+        }
+
+        Ok(loaded)
+    }
+
+    // every tile an autotile table resolves to must exist for every item
+    fn table_fits(&self, table: &AutotileTable, entry: &FormatEntry) -> bool {
+        table.tiles.len() == 256 &&
+        table.tiles.iter().all(|t| {
+            let (part, index) = (t[0], t[1]);
+            part < entry.parts.len() &&
+            entry.tiles.values().all(|&tile| index < self.part_len(tile, part))
+        })
+    }
+
+    fn uv_rect(&self, rect: &AtlasRect) -> Option<UvRect> {
+        let page = match self.pages.get(rect.page) {
+            Some(page) => page,
+            None => return None,
+        };
+        let (w, h) = page.dimensions();
+        if rect.x + rect.w > w as usize || rect.y + rect.h > h as usize {
+            return None;
+        }
+        Some(UvRect {
+            page: rect.page,
+            min: [rect.x as f32 / w as f32, rect.y as f32 / h as f32],
+            max: [(rect.x + rect.w) as f32 / w as f32, (rect.y + rect.h) as f32 / h as f32],
+        })
+    }
+
+    pub fn format(&self, name: &str) -> Option<FormatId> {
+        self.format_ids.get(name).cloned()
+    }
+
+    pub fn format_name(&self, format: FormatId) -> &str {
+        &self.formats[format.0 as usize].name
+    }
+
+    /// The part names of a format, a part's position in this list is its index.
+    pub fn parts(&self, format: FormatId) -> &[String] {
+        &self.formats[format.0 as usize].parts
+    }
+
+    pub fn part(&self, format: FormatId, name: &str) -> Option<usize> {
+        self.parts(format).iter().position(|p| p == name)
+    }
+
+    /// How the tiles of a format are picked from their neighbors, if the
+    /// format has autotile rules.
+    pub fn autotile(&self, format: FormatId) -> Option<&AutotileTable> {
+        self.formats[format.0 as usize].autotile.as_ref()
+    }
+
+    pub fn tile(&self, format: FormatId, id: &str) -> Option<TileId> {
+        self.formats[format.0 as usize].tiles.get(id).cloned()
+    }
+
+    pub fn tile_name(&self, tile: TileId) -> &str {
+        &self.tiles[tile.0 as usize].id
+    }
+
+    pub fn tile_format(&self, tile: TileId) -> FormatId {
+        self.tiles[tile.0 as usize].format
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn part_len(&self, tile: TileId, part: usize) -> usize {
+        self.tiles[tile.0 as usize].frames[0].get(part).map_or(0, |&(_, len)| len)
+    }
+
+    pub fn properties(&self, tile: TileId, part: usize) -> Option<&TileProperties> {
+        self.tiles[tile.0 as usize].properties.get(part)
+    }
+
+    /// How many frames a tile has, 1 unless it's animated.
+    pub fn num_frames(&self, tile: TileId) -> usize {
+        self.tiles[tile.0 as usize].frames.len()
+    }
+
+    /// The frame of a tile to draw `time_ms` into its animation.
+    pub fn frame_at(&self, tile: TileId, time_ms: u64) -> usize {
+        frame_at(self.tiles[tile.0 as usize].durations.iter().cloned(), time_ms)
+    }
+
+    fn index(&self, tile: TileId, frame: usize, part: usize, index: usize) -> Option<usize> {
+        self.tiles[tile.0 as usize]
+            .frames
+            .get(frame)
+            .and_then(|parts| parts.get(part))
+            .and_then(|&(start, len)| {
+                if index < len {
+                    Some(start + index)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// The rect of a tile's first frame.
+    pub fn rect(&self, tile: TileId, part: usize, index: usize) -> Option<&AtlasRect> {
+        self.frame_rect(tile, 0, part, index)
+    }
+
+    /// The uv rect of a tile's first frame.
+    pub fn uv(&self, tile: TileId, part: usize, index: usize) -> Option<&UvRect> {
+        self.frame_uv(tile, 0, part, index)
+    }
+
+    pub fn frame_rect(&self, tile: TileId, frame: usize, part: usize, index: usize) -> Option<&AtlasRect> {
+        self.index(tile, frame, part, index).map(|i| &self.rects[i])
+    }
+
+    pub fn frame_uv(&self, tile: TileId, frame: usize, part: usize, index: usize) -> Option<&UvRect> {
+        self.index(tile, frame, part, index).map(|i| &self.uvs[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use compile_tile_set;
+
+    #[test]
+    fn compiled_tile_sets_load_back() {
+        compile_tile_set(&Path::new("test_data/src"),
+                         &Path::new("tile_set_sources/morning"),
+                         &Path::new("test_data/target"),
+                         &Path::new("tile_sets/morning_loaded"))
+            .expect("compilation failed");
+        let ts = LoadedTileSet::open(Path::new("test_data/target/tile_sets/morning_loaded.json"))
+            .expect("loading failed");
+
+        let floor = ts.format("output_tile_formats/floor").expect("no floor format");
+        let grass = ts.tile(floor, "morning_grass").expect("no grass");
+        assert_eq!(ts.tile_name(grass), "morning_grass");
+        assert_eq!(ts.tile_format(grass), floor);
+        assert!(ts.tile(floor, "morning_brick_wall").is_none());
+
+        let numpad = ts.part(floor, "numpad").expect("no numpad part");
+        assert_eq!(ts.part_len(grass, numpad), 9);
+        let uv = ts.uv(grass, numpad, 2).expect("no uv");
+        let rect = ts.rect(grass, numpad, 2).expect("no rect");
+        let (w, h) = ts.pages[rect.page].dimensions();
+        assert_eq!(uv.min, [rect.x as f32 / w as f32, rect.y as f32 / h as f32]);
+        assert!(uv.max[0] <= 1.0 && uv.max[1] <= 1.0);
+        assert!(ts.uv(grass, numpad, 9).is_none());
+
+        let table = ts.autotile(floor).expect("no autotile table");
+        assert_eq!(table.resolve(0xff), [numpad, 4]);
+        assert_eq!(ts.properties(grass, numpad).and_then(|p| p.movement_cost), Some(1.5));
+        assert_eq!(ts.num_frames(grass), 1);
+        assert_eq!(ts.frame_at(grass, 12345), 0);
+    }
+
+    #[test]
+    fn animated_tiles_load_every_frame() {
+        compile_tile_set(&Path::new("test_data/src"),
+                         &Path::new("tile_set_sources/blob"),
+                         &Path::new("test_data/target"),
+                         &Path::new("tile_sets/blob_loaded"))
+            .expect("compilation failed");
+        let ts = LoadedTileSet::open(Path::new("test_data/target/tile_sets/blob_loaded.json"))
+            .expect("loading failed");
+
+        let blob = ts.format("output_tile_formats/blob47").expect("no blob format");
+        let water = ts.tile(blob, "blue_water").expect("no blue water");
+        let purple = ts.tile(blob, "purple_water").expect("no purple water");
+        assert_eq!(ts.num_frames(water), 2);
+        assert_eq!(ts.frame_at(water, 0), 0);
+        assert_eq!(ts.frame_at(water, 249), 0);
+        assert_eq!(ts.frame_at(water, 250), 1);
+        assert_eq!(ts.frame_at(water, 749), 1);
+        assert_eq!(ts.frame_at(water, 750), 0);
+
+        // the second frame is the purple water, shared in the atlas
+        assert_eq!(ts.frame_rect(water, 1, 0, 46), ts.rect(purple, 0, 46));
+        assert!(ts.frame_rect(water, 2, 0, 0).is_none());
+    }
+}
@@ -0,0 +1,469 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use serde_json;
+use serde_json::value::Value;
+
+use {Error, TileSet, TileSetResult};
+
+/// The version of the map files written by `TileMap::save`.
+pub const MAP_VERSION: u32 = 1;
+const BINARY_MAGIC: &'static [u8] = b"CPMAP";
+
+pub const FLIP_HORIZONTAL: u32 = 0x8000_0000;
+pub const FLIP_VERTICAL: u32 = 0x4000_0000;
+/// Swaps the x and y axes, applied before the other two flips.
+pub const FLIP_DIAGONAL: u32 = 0x2000_0000;
+const FLIPS: u32 = FLIP_HORIZONTAL | FLIP_VERTICAL | FLIP_DIAGONAL;
+
+/// One cell of a layer: 0 when empty, otherwise the palette index plus one,
+/// along with any of the `FLIP_*` bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Cell(pub u32);
+
+impl Cell {
+    pub fn empty() -> Cell {
+        Cell(0)
+    }
+
+    pub fn new(palette_index: usize, flips: u32) -> Cell {
+        Cell((palette_index as u32 + 1) | (flips & FLIPS))
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 & !FLIPS == 0
+    }
+
+    /// The palette index of the cell's item.
+    pub fn item(self) -> Option<usize> {
+        match self.0 & !FLIPS {
+            0 => None,
+            n => Some(n as usize - 1),
+        }
+    }
+
+    pub fn flips(self) -> u32 {
+        self.0 & FLIPS
+    }
+}
+
+/// An item of the tile set, a map refers to each one it uses just once.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaletteEntry {
+    pub fmt: String,
+    pub id: String,
+}
+
+/// A full grid of cells, row by row from the top left.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub name: String,
+    pub cells: Vec<Cell>,
+}
+
+/// A grid of tile set items, drawn layer by layer, first to last.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TileMap {
+    pub size: [usize; 2],
+    pub tile_size: [usize; 2],
+    pub palette: Vec<PaletteEntry>,
+    pub layers: Vec<Layer>,
+}
+
+impl TileMap {
+    pub fn new(size: [usize; 2], tile_size: [usize; 2]) -> TileMap {
+        TileMap {
+            size: size,
+            tile_size: tile_size,
+            palette: Vec::new(),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Adds an empty layer on top of the others and returns its index.
+    pub fn add_layer(&mut self, name: &str) -> usize {
+        self.layers.push(Layer {
+            name: name.to_string(),
+            cells: vec![Cell::empty(); self.size[0] * self.size[1]],
+        });
+        self.layers.len() - 1
+    }
+
+    pub fn layer(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|l| l.name == name)
+    }
+
+    /// The palette index of an item, which is added if the map didn't use
+    /// it before.
+    pub fn palette_index(&mut self, fmt: &str, id: &str) -> usize {
+        match self.palette.iter().position(|e| e.fmt == fmt && e.id == id) {
+            Some(i) => i,
+            None => {
+                self.palette.push(PaletteEntry {
+                    fmt: fmt.to_string(),
+                    id: id.to_string(),
+                });
+                self.palette.len() - 1
+            }
+        }
+    }
+
+    fn index(&self, pos: [usize; 2]) -> Option<usize> {
+        if pos[0] < self.size[0] && pos[1] < self.size[1] {
+            Some(pos[1] * self.size[0] + pos[0])
+        } else {
+            None
+        }
+    }
+
+    /// Empty outside of the map.
+    pub fn get(&self, layer: usize, pos: [usize; 2]) -> Cell {
+        self.index(pos).map_or(Cell::empty(), |i| self.layers[layer].cells[i])
+    }
+
+    /// Returns false, changing nothing, when `pos` is outside of the map.
+    pub fn set(&mut self, layer: usize, pos: [usize; 2], cell: Cell) -> bool {
+        match self.index(pos) {
+            Some(i) => {
+                self.layers[layer].cells[i] = cell;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The palette entry of a cell, if it isn't empty.
+    pub fn entry(&self, cell: Cell) -> Option<&PaletteEntry> {
+        cell.item().and_then(|i| self.palette.get(i))
+    }
+
+    /// Loads a map saved by `save`, as JSON when the extension is `json`
+    /// and in the binary format otherwise.
+    pub fn load(path: &Path) -> TileSetResult<TileMap> {
+        let mut bytes = Vec::new();
+        try!(File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut bytes))
+            .map_err(|err| Error::IOError(path.to_path_buf(), err)));
+
+        // the version is checked before the map, whose layout could differ
+        let map = if is_json(path) {
+            let value: Value = try!(serde_json::de::from_slice(&bytes)
+                .map_err(|err| Error::JsonError(path.to_path_buf(), err)));
+            let version = value.find("version").and_then(|v| v.as_u64()).unwrap_or(0);
+            if version != MAP_VERSION as u64 {
+                return Err(Error::UnsupportedMapVersion {
+                    path: path.to_path_buf(),
+                    version: version as u32,
+                });
+            }
+            try!(serde_json::value::from_value(value.find("map").cloned().unwrap_or(Value::Null))
+                .map_err(|err| Error::JsonError(path.to_path_buf(), err)))
+        } else {
+            match try!(from_binary(&bytes).map_err(|reason| bad(path, reason))) {
+                (MAP_VERSION, map) => map,
+                (version, _) => {
+                    return Err(Error::UnsupportedMapVersion {
+                        path: path.to_path_buf(),
+                        version: version,
+                    })
+                }
+            }
+        };
+
+        for layer in &map.layers {
+            if layer.cells.len() != map.size[0] * map.size[1] {
+                return Err(bad(path, format!("layer `{}` doesn't match the map's size", layer.name)));
+            }
+        }
+        Ok(map)
+    }
+
+    /// Saves the map as JSON when the extension is `json`, and in the much
+    /// smaller binary format otherwise.
+    pub fn save(&self, path: &Path) -> TileSetResult<()> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir).map_err(|err| Error::IOError(dir.to_path_buf(), err)));
+        }
+        let mut writer = try!(File::create(path).map_err(|err| Error::IOError(path.to_path_buf(), err)));
+        if is_json(path) {
+            let file = MapFile {
+                version: MAP_VERSION,
+                map: self.clone(),
+            };
+            serde_json::ser::to_writer(&mut writer, &file).map_err(|err| Error::JsonError(path.to_path_buf(), err))
+        } else {
+            writer.write_all(&to_binary(self)).map_err(|err| Error::IOError(path.to_path_buf(), err))
+        }
+    }
+
+    /// Every problem with the map when drawn with `ts`: items missing from
+    /// it and cells outside of the palette. `path` is only used for error
+    /// reporting.
+    pub fn validate(&self, path: &Path, ts: &TileSet) -> Vec<Error> {
+        let mut errors = Vec::new();
+        if self.tile_size != ts.tile_size {
+            errors.push(bad(path, "the map's tile size doesn't match the tile set"));
+        }
+        for entry in &self.palette {
+            if !ts.fmts.get(&entry.fmt).map_or(false, |items| items.contains_key(&entry.id)) {
+                errors.push(Error::UnknownMapItem {
+                    path: path.to_path_buf(),
+                    fmt: entry.fmt.clone(),
+                    id: entry.id.clone(),
+                });
+            }
+        }
+        for layer in &self.layers {
+            for (i, cell) in layer.cells.iter().enumerate() {
+                if cell.item().map_or(false, |item| item >= self.palette.len()) {
+                    errors.push(Error::BadMapCell {
+                        path: path.to_path_buf(),
+                        layer: layer.name.clone(),
+                        pos: [i % self.size[0], i / self.size[0]],
+                    });
+                }
+            }
+        }
+        errors
+    }
+}
+
+// how maps are stored as JSON
+#[derive(Serialize)]
+struct MapFile {
+    version: u32,
+    map: TileMap,
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json")
+}
+
+fn bad<S: Into<String>>(path: &Path, reason: S) -> Error {
+    Error::BadMapFile {
+        path: path.to_path_buf(),
+        reason: reason.into(),
+    }
+}
+
+// the binary format is the magic bytes followed by little endian u32s:
+// version, size, tile size, the palette and the layers, where strings are
+// a length and utf8 bytes, and every list starts with its length
+fn to_binary(map: &TileMap) -> Vec<u8> {
+    fn put(out: &mut Vec<u8>, n: u32) {
+        out.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+    }
+    fn put_str(out: &mut Vec<u8>, s: &str) {
+        put(out, s.len() as u32);
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    let mut out = BINARY_MAGIC.to_vec();
+    put(&mut out, MAP_VERSION);
+    for &n in map.size.iter().chain(&map.tile_size) {
+        put(&mut out, n as u32);
+    }
+    put(&mut out, map.palette.len() as u32);
+    for entry in &map.palette {
+        put_str(&mut out, &entry.fmt);
+        put_str(&mut out, &entry.id);
+    }
+    put(&mut out, map.layers.len() as u32);
+    for layer in &map.layers {
+        put_str(&mut out, &layer.name);
+        for cell in &layer.cells {
+            put(&mut out, cell.0);
+        }
+    }
+    out
+}
+
+struct BinaryReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> BinaryReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < n {
+            return Err(String::from("the map ends early"));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = try!(self.take(4));
+        Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = try!(self.u32()) as usize;
+        let bytes = try!(self.take(len));
+        String::from_utf8(bytes.to_vec()).map_err(|_| String::from("a name isn't valid utf8"))
+    }
+}
+
+// returns the version along with the map, which is only read in full if
+// the version matches
+fn from_binary(bytes: &[u8]) -> Result<(u32, TileMap), String> {
+    let mut r = BinaryReader { bytes: bytes };
+    if try!(r.take(BINARY_MAGIC.len())) != BINARY_MAGIC {
+        return Err(String::from("not a map file"));
+    }
+    let version = try!(r.u32());
+    if version != MAP_VERSION {
+        return Ok((version, TileMap::new([0, 0], [0, 0])));
+    }
+
+    let size = [try!(r.u32()) as usize, try!(r.u32()) as usize];
+    let tile_size = [try!(r.u32()) as usize, try!(r.u32()) as usize];
+    let mut map = TileMap::new(size, tile_size);
+    for _ in 0..try!(r.u32()) {
+        let fmt = try!(r.string());
+        let id = try!(r.string());
+        map.palette.push(PaletteEntry {
+            fmt: fmt,
+            id: id,
+        });
+    }
+    for _ in 0..try!(r.u32()) {
+        let name = try!(r.string());
+        // checked against what's left so a bad size can't allocate much
+        let count = size[0].saturating_mul(size[1]);
+        if count > r.bytes.len() / 4 {
+            return Err(String::from("the map ends early"));
+        }
+        let mut cells = Vec::with_capacity(count);
+        for _ in 0..count {
+            cells.push(Cell(try!(r.u32())));
+        }
+        map.layers.push(Layer {
+            name: name,
+            cells: cells,
+        });
+    }
+    if !r.bytes.is_empty() {
+        return Err(String::from("the map has trailing bytes"));
+    }
+    Ok((MAP_VERSION, map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{from_binary, to_binary};
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use {compile_tile_set, load, Error, TileSet};
+
+    fn sample() -> TileMap {
+        let mut map = TileMap::new([3, 2], [16, 16]);
+        let ground = map.add_layer("ground");
+        let walls = map.add_layer("walls");
+        let grass = map.palette_index("output_tile_formats/floor", "morning_grass");
+        let wall = map.palette_index("output_tile_formats/wall", "morning_brick_wall");
+        for x in 0..3 {
+            for y in 0..2 {
+                map.set(ground, [x, y], Cell::new(grass, 0));
+            }
+        }
+        map.set(walls, [1, 0], Cell::new(wall, FLIP_HORIZONTAL));
+        map
+    }
+
+    #[test]
+    fn cells_keep_items_and_flips_apart() {
+        let cell = Cell::new(4, FLIP_HORIZONTAL | FLIP_DIAGONAL);
+        assert_eq!(cell.item(), Some(4));
+        assert_eq!(cell.flips(), FLIP_HORIZONTAL | FLIP_DIAGONAL);
+        assert!(!cell.is_empty());
+        assert!(Cell::empty().is_empty());
+        assert_eq!(Cell::empty().item(), None);
+    }
+
+    #[test]
+    fn cells_are_set_within_bounds() {
+        let mut map = TileMap::new([3, 2], [16, 16]);
+        let ground = map.add_layer("ground");
+        let grass = map.palette_index("output_tile_formats/floor", "morning_grass");
+        assert_eq!(map.palette_index("output_tile_formats/floor", "morning_grass"), grass);
+
+        assert!(map.set(ground, [2, 1], Cell::new(grass, 0)));
+        assert!(!map.set(ground, [3, 0], Cell::new(grass, 0)));
+        assert_eq!(map.layers[ground].cells[5], Cell::new(grass, 0));
+        assert_eq!(map.entry(map.get(ground, [2, 1])).map(|e| &e.id[..]), Some("morning_grass"));
+        assert!(map.get(ground, [0, 5]).is_empty());
+    }
+
+    #[test]
+    fn maps_round_trip() {
+        let map = sample();
+        for name in &["sample.json", "sample.cmap"] {
+            let path = Path::new("test_data/target/maps").join(name);
+            map.save(&path).expect("couldn't save map");
+            assert_eq!(TileMap::load(&path).expect("couldn't load map"), map);
+        }
+
+        let bytes = to_binary(&map);
+        assert!(from_binary(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_binary(b"CPMAX").is_err());
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let path = Path::new("test_data/target/maps/future.json");
+        fs::create_dir_all(path.parent().unwrap()).expect("couldn't create folder");
+        File::create(path)
+            .and_then(|mut f| f.write_all(br#"{"version": 2, "map": null}"#))
+            .expect("couldn't write map");
+        match TileMap::load(path) {
+            Err(Error::UnsupportedMapVersion { version: 2, .. }) => {}
+            other => panic!("expected a version error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn maps_are_validated_against_the_tile_set() {
+        compile_tile_set(Path::new("test_data/src"),
+                         Path::new("tile_set_sources/morning"),
+                         Path::new("test_data/target"),
+                         Path::new("tile_sets/morning_maps"))
+            .expect("compilation failed");
+        let ts: TileSet = load(Path::new("test_data/target/tile_sets/morning_maps").to_path_buf())
+            .expect("couldn't read compiled tile set");
+
+        let mut map = sample();
+        assert!(map.validate(Path::new("sample.json"), &ts).is_empty());
+
+        map.palette_index("output_tile_formats/floor", "evening_grass");
+        map.set(0, [0, 0], Cell::new(7, 0));
+        let errors = map.validate(Path::new("sample.json"), &ts);
+        assert_eq!(errors.len(), 2);
+        match errors[0] {
+            Error::UnknownMapItem { ref id, .. } => assert_eq!(id, "evening_grass"),
+            ref other => panic!("expected an unknown item, got {:?}", other),
+        }
+        match errors[1] {
+            Error::BadMapCell { ref layer, pos, .. } => assert_eq!((&layer[..], pos), ("ground", [0, 0])),
+            ref other => panic!("expected a bad cell, got {:?}", other),
+        }
+    }
+}
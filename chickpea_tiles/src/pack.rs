@@ -0,0 +1,498 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use TileRect;
+
+/// Places rectangles into a fixed size atlas, one at a time.
+pub trait Packer {
+    fn insert(&mut self, size: [usize; 2]) -> Option<TileRect>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Shelf,
+    MaxRects,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "shelf" => Some(Algorithm::Shelf),
+            "max_rects" => Some(Algorithm::MaxRects),
+            _ => None,
+        }
+    }
+
+    pub fn packer(self, size: [usize; 2]) -> Box<Packer> {
+        match self {
+            Algorithm::Shelf => Box::new(ShelfPacker::new(size)),
+            Algorithm::MaxRects => Box::new(MaxRectsPacker::new(size)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PackOptions {
+    pub algorithm: Algorithm,
+    pub max_size: [usize; 2],
+    /// When set the atlas is the smallest power of two size that fits,
+    /// otherwise everything is packed into `max_size` and trimmed afterwards.
+    pub power_of_two: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Packing {
+    pub size: [usize; 2],
+    /// One rect for every input size, in input order.
+    pub rects: Vec<TileRect>,
+}
+
+impl Packing {
+    /// The fraction of the atlas covered by packed rects.
+    pub fn utilization(&self) -> f64 {
+        let used = self.rects.iter().map(|r| r.w * r.h).fold(0, |x, y| x + y);
+        let total = self.size[0] * self.size[1];
+        if total == 0 {
+            0.0
+        } else {
+            used as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub page: usize,
+    pub rect: TileRect,
+}
+
+#[derive(Clone, Debug)]
+pub struct PagedPacking {
+    pub pages: Vec<[usize; 2]>,
+    /// One placement for every input size, in input order.
+    pub placements: Vec<Placement>,
+}
+
+impl PagedPacking {
+    /// The fraction of all pages covered by packed rects.
+    pub fn utilization(&self) -> f64 {
+        let used = self.placements.iter().map(|p| p.rect.w * p.rect.h).fold(0, |x, y| x + y);
+        let total = self.pages.iter().map(|s| s[0] * s[1]).fold(0, |x, y| x + y);
+        if total == 0 {
+            0.0
+        } else {
+            used as f64 / total as f64
+        }
+    }
+}
+
+// tallest (then widest) first works well for both algorithms
+fn sort_order(sizes: &[[usize; 2]], indices: &mut [usize]) {
+    indices.sort_by(|&a, &b| (sizes[b][1], sizes[b][0]).cmp(&(sizes[a][1], sizes[a][0])));
+}
+
+fn pack_into(algorithm: Algorithm,
+             atlas_size: [usize; 2],
+             sizes: &[[usize; 2]],
+             order: &[usize])
+             -> Option<Vec<TileRect>> {
+    let mut packer = algorithm.packer(atlas_size);
+    let mut rects = vec![TileRect { x: 0, y: 0, w: 0, h: 0 }; sizes.len()];
+    for &i in order {
+        match packer.insert(sizes[i]) {
+            Some(rect) => rects[i] = rect,
+            None => return None,
+        }
+    }
+    Some(rects)
+}
+
+fn powers_of_two(max: usize) -> Vec<usize> {
+    let mut v = Vec::new();
+    let mut n = 1;
+    while n <= max {
+        v.push(n);
+        n *= 2;
+    }
+    v
+}
+
+/// Packs every size into a single atlas no bigger than `opts.max_size`,
+/// returning `None` when they don't all fit.
+pub fn pack(sizes: &[[usize; 2]], opts: &PackOptions) -> Option<Packing> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    sort_order(sizes, &mut order);
+
+    if opts.power_of_two {
+        let area = sizes.iter().map(|s| s[0] * s[1]).fold(0, |x, y| x + y);
+        let widest = sizes.iter().map(|s| s[0]).max().unwrap_or(0);
+        let tallest = sizes.iter().map(|s| s[1]).max().unwrap_or(0);
+
+        let mut candidates = Vec::new();
+        for &w in &powers_of_two(opts.max_size[0]) {
+            for &h in &powers_of_two(opts.max_size[1]) {
+                if w * h >= area && w >= widest && h >= tallest {
+                    candidates.push([w, h]);
+                }
+            }
+        }
+        // smallest area first, preferring squarer atlases
+        candidates.sort_by_key(|s| (s[0] * s[1], cmp::max(s[0], s[1])));
+
+        for size in candidates {
+            if let Some(rects) = pack_into(opts.algorithm, size, sizes, &order) {
+                return Some(Packing {
+                    size: size,
+                    rects: rects,
+                });
+            }
+        }
+        None
+    } else {
+        pack_into(opts.algorithm, opts.max_size, sizes, &order).map(|rects| {
+            let w = rects.iter().map(|r| r.x + r.w).max().unwrap_or(0);
+            let h = rects.iter().map(|r| r.y + r.h).max().unwrap_or(0);
+            Packing {
+                size: [w, h],
+                rects: rects,
+            }
+        })
+    }
+}
+
+/// Packs every size into as many pages of at most `opts.max_size` as it
+/// takes, returning `None` only when a single size can't fit on a page.
+pub fn pack_pages(sizes: &[[usize; 2]], opts: &PackOptions) -> Option<PagedPacking> {
+    let mut pages = Vec::new();
+    let mut placements = vec![Placement {
+                                  page: 0,
+                                  rect: TileRect { x: 0, y: 0, w: 0, h: 0 },
+                              };
+                              sizes.len()];
+    let mut remaining: Vec<usize> = (0..sizes.len()).collect();
+
+    while !remaining.is_empty() {
+        let page = pages.len();
+        let subset: Vec<[usize; 2]> = remaining.iter().map(|&i| sizes[i]).collect();
+        if let Some(packing) = pack(&subset, opts) {
+            for (&i, &rect) in remaining.iter().zip(&packing.rects) {
+                placements[i] = Placement {
+                    page: page,
+                    rect: rect,
+                };
+            }
+            pages.push(packing.size);
+            break;
+        }
+
+        // fill up a page, and carry whatever didn't fit over to the next one
+        sort_order(sizes, &mut remaining);
+        let mut packer = opts.algorithm.packer(opts.max_size);
+        let mut placed = Vec::new();
+        let mut rest = Vec::new();
+        for &i in &remaining {
+            match packer.insert(sizes[i]) {
+                Some(rect) => placed.push((i, rect)),
+                None => rest.push(i),
+            }
+        }
+        if placed.is_empty() {
+            return None;
+        }
+
+        // repacking on its own usually gives a smaller page
+        let page_sizes: Vec<[usize; 2]> = placed.iter().map(|&(i, _)| sizes[i]).collect();
+        match pack(&page_sizes, opts) {
+            Some(packing) => {
+                for (&(i, _), &rect) in placed.iter().zip(&packing.rects) {
+                    placements[i] = Placement {
+                        page: page,
+                        rect: rect,
+                    };
+                }
+                pages.push(packing.size);
+            }
+            None => {
+                for &(i, rect) in &placed {
+                    placements[i] = Placement {
+                        page: page,
+                        rect: rect,
+                    };
+                }
+                pages.push(opts.max_size);
+            }
+        }
+        remaining = rest;
+    }
+
+    Some(PagedPacking {
+        pages: pages,
+        placements: placements,
+    })
+}
+
+struct Shelf {
+    y: usize,
+    height: usize,
+    used: usize,
+}
+
+/// Packs rects left to right into horizontal shelves, cheap and good
+/// enough when most rects have the same height.
+pub struct ShelfPacker {
+    size: [usize; 2],
+    shelves: Vec<Shelf>,
+}
+
+impl ShelfPacker {
+    pub fn new(size: [usize; 2]) -> ShelfPacker {
+        ShelfPacker {
+            size: size,
+            shelves: Vec::new(),
+        }
+    }
+}
+
+impl Packer for ShelfPacker {
+    fn insert(&mut self, size: [usize; 2]) -> Option<TileRect> {
+        let width = self.size[0];
+        let best = self.shelves
+            .iter()
+            .enumerate()
+            .filter(|&(_, s)| s.height >= size[1] && width - s.used >= size[0])
+            .min_by_key(|&(_, s)| s.height - size[1])
+            .map(|(i, _)| i);
+
+        let i = match best {
+            Some(i) => i,
+            None => {
+                let y = self.shelves.last().map_or(0, |s| s.y + s.height);
+                if size[0] > width || y + size[1] > self.size[1] {
+                    return None;
+                }
+                self.shelves.push(Shelf {
+                    y: y,
+                    height: size[1],
+                    used: 0,
+                });
+                self.shelves.len() - 1
+            }
+        };
+
+        let shelf = &mut self.shelves[i];
+        let rect = TileRect {
+            x: shelf.used,
+            y: shelf.y,
+            w: size[0],
+            h: size[1],
+        };
+        shelf.used += size[0];
+        Some(rect)
+    }
+}
+
+/// The MaxRects algorithm with the best short side fit heuristic, which
+/// tracks every maximal free rectangle left in the atlas.
+pub struct MaxRectsPacker {
+    free: Vec<TileRect>,
+}
+
+impl MaxRectsPacker {
+    pub fn new(size: [usize; 2]) -> MaxRectsPacker {
+        MaxRectsPacker {
+            free: vec![TileRect {
+                           x: 0,
+                           y: 0,
+                           w: size[0],
+                           h: size[1],
+                       }],
+        }
+    }
+
+    fn split(&mut self, used: &TileRect) {
+        let mut i = 0;
+        while i < self.free.len() {
+            let f = self.free[i];
+            if !intersects(&f, used) {
+                i += 1;
+                continue;
+            }
+            self.free.swap_remove(i);
+            if used.x > f.x {
+                self.free.push(TileRect { w: used.x - f.x, ..f });
+            }
+            if used.x + used.w < f.x + f.w {
+                self.free.push(TileRect {
+                    x: used.x + used.w,
+                    w: f.x + f.w - (used.x + used.w),
+                    ..f
+                });
+            }
+            if used.y > f.y {
+                self.free.push(TileRect { h: used.y - f.y, ..f });
+            }
+            if used.y + used.h < f.y + f.h {
+                self.free.push(TileRect {
+                    y: used.y + used.h,
+                    h: f.y + f.h - (used.y + used.h),
+                    ..f
+                });
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        let mut i = 0;
+        while i < self.free.len() {
+            let contained = (0..self.free.len())
+                .any(|j| i != j && contains(&self.free[j], &self.free[i]) &&
+                         (self.free[i] != self.free[j] || j < i));
+            if contained {
+                self.free.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+fn intersects(a: &TileRect, b: &TileRect) -> bool {
+    a.x < b.x + b.w && b.x < a.x + a.w && a.y < b.y + b.h && b.y < a.y + a.h
+}
+
+fn contains(outer: &TileRect, inner: &TileRect) -> bool {
+    inner.x >= outer.x && inner.y >= outer.y && inner.x + inner.w <= outer.x + outer.w &&
+    inner.y + inner.h <= outer.y + outer.h
+}
+
+impl Packer for MaxRectsPacker {
+    fn insert(&mut self, size: [usize; 2]) -> Option<TileRect> {
+        let best = self.free
+            .iter()
+            .filter(|f| f.w >= size[0] && f.h >= size[1])
+            .min_by_key(|f| {
+                let dw = f.w - size[0];
+                let dh = f.h - size[1];
+                (cmp::min(dw, dh), cmp::max(dw, dh), f.y, f.x)
+            })
+            .cloned();
+
+        best.map(|f| {
+            let rect = TileRect {
+                x: f.x,
+                y: f.y,
+                w: size[0],
+                h: size[1],
+            };
+            self.split(&rect);
+            self.prune();
+            rect
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TileRect;
+
+    fn check(packing: &Packing) {
+        for (i, a) in packing.rects.iter().enumerate() {
+            assert!(a.x + a.w <= packing.size[0] && a.y + a.h <= packing.size[1]);
+            for b in &packing.rects[i + 1..] {
+                assert!(!(a.x < b.x + b.w && b.x < a.x + a.w && a.y < b.y + b.h &&
+                          b.y < a.y + a.h),
+                        "{:?} overlaps {:?}",
+                        a,
+                        b);
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_tiles_fill_power_of_two_atlas() {
+        let sizes = vec![[16, 16]; 64];
+        for &algorithm in &[Algorithm::Shelf, Algorithm::MaxRects] {
+            let packing = pack(&sizes,
+                               &PackOptions {
+                                   algorithm: algorithm,
+                                   max_size: [1024, 1024],
+                                   power_of_two: true,
+                               })
+                .expect("packing failed");
+            check(&packing);
+            assert_eq!(packing.size, [128, 128]);
+            assert_eq!(packing.utilization(), 1.0);
+        }
+    }
+
+    #[test]
+    fn mixed_sizes_respect_max_size() {
+        let sizes = vec![[32, 16], [16, 16], [16, 48], [8, 8], [64, 8], [24, 24], [16, 16]];
+        for &algorithm in &[Algorithm::Shelf, Algorithm::MaxRects] {
+            let opts = PackOptions {
+                algorithm: algorithm,
+                max_size: [64, 128],
+                power_of_two: false,
+            };
+            let packing = pack(&sizes, &opts).expect("packing failed");
+            check(&packing);
+            assert!(packing.size[0] <= 64 && packing.size[1] <= 128);
+            assert!(packing.utilization() > 0.5);
+        }
+    }
+
+    #[test]
+    fn too_much_doesnt_fit() {
+        let sizes = vec![[16, 16]; 17];
+        let opts = PackOptions {
+            algorithm: Algorithm::MaxRects,
+            max_size: [64, 64],
+            power_of_two: true,
+        };
+        assert!(pack(&sizes, &opts).is_none());
+        let mut packer = MaxRectsPacker::new([16, 16]);
+        assert_eq!(packer.insert([16, 16]),
+                   Some(TileRect { x: 0, y: 0, w: 16, h: 16 }));
+        assert_eq!(packer.insert([1, 1]), None);
+    }
+
+    #[test]
+    fn overflow_spills_onto_more_pages() {
+        let sizes = vec![[16, 16]; 40];
+        let opts = PackOptions {
+            algorithm: Algorithm::MaxRects,
+            max_size: [64, 64],
+            power_of_two: true,
+        };
+        let paged = pack_pages(&sizes, &opts).expect("packing failed");
+        assert_eq!(paged.pages, vec![[64, 64], [64, 64], [32, 64]]);
+        for page in 0..paged.pages.len() {
+            let packing = Packing {
+                size: paged.pages[page],
+                rects: paged.placements
+                    .iter()
+                    .filter(|p| p.page == page)
+                    .map(|p| p.rect)
+                    .collect(),
+            };
+            check(&packing);
+        }
+
+        assert!(pack_pages(&[[128, 16]], &opts).is_none());
+    }
+}
@@ -0,0 +1,150 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use serde_json::value::Value;
+
+use {Error, TileRect};
+
+/// Gameplay properties of a tile, every field is optional. Input formats
+/// give them per part and items override them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TileProperties {
+    #[serde(default)]
+    pub solid: Option<bool>,
+    /// Collision rects in pixels, relative to the tile's top left. A solid
+    /// tile without any collides over its whole area.
+    #[serde(default)]
+    pub collision: Vec<TileRect>,
+    /// What walking over the tile costs, 1 being a regular floor.
+    #[serde(default)]
+    pub movement_cost: Option<f32>,
+    /// Anything else the game wants to know about the tile.
+    #[serde(default)]
+    pub custom: BTreeMap<String, Value>,
+}
+
+impl TileProperties {
+    pub fn is_empty(&self) -> bool {
+        *self == TileProperties::default()
+    }
+
+    /// `over` layered on top of `self`, fields set in `over` win.
+    pub fn merged(&self, over: &TileProperties) -> TileProperties {
+        let mut custom = self.custom.clone();
+        custom.extend(over.custom.iter().map(|(k, v)| (k.clone(), v.clone())));
+        TileProperties {
+            solid: over.solid.or(self.solid),
+            collision: if over.collision.is_empty() {
+                self.collision.clone()
+            } else {
+                over.collision.clone()
+            },
+            movement_cost: over.movement_cost.or(self.movement_cost),
+            custom: custom,
+        }
+    }
+
+    /// Every problem with the properties of `owner`, an item or a part.
+    pub fn validate(&self, path: &Path, owner: &str, tile_size: [usize; 2]) -> Vec<Error> {
+        let mut bad = Vec::new();
+        let fits = |r: &TileRect| {
+            r.w > 0 && r.h > 0 && r.w <= tile_size[0] && r.h <= tile_size[1] &&
+            r.x <= tile_size[0] - r.w && r.y <= tile_size[1] - r.h
+        };
+        if !self.collision.iter().all(fits) {
+            bad.push("collision");
+        }
+        if let Some(cost) = self.movement_cost {
+            if !cost.is_finite() || cost < 0.0 {
+                bad.push("movement_cost");
+            }
+        }
+        if self.custom.keys().any(|k| k.is_empty()) {
+            bad.push("custom");
+        }
+
+        bad.into_iter()
+            .map(|property| {
+                Error::BadProperty {
+                    path: path.to_path_buf(),
+                    owner: owner.to_string(),
+                    property: property,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use serde_json::value::Value;
+    use {Error, TileRect};
+
+    fn rect(x: usize, y: usize, w: usize, h: usize) -> TileRect {
+        TileRect {
+            x: x,
+            y: y,
+            w: w,
+            h: h,
+        }
+    }
+
+    #[test]
+    fn items_override_parts() {
+        let mut part = TileProperties::default();
+        part.solid = Some(true);
+        part.movement_cost = Some(2.0);
+        part.collision = vec![rect(0, 0, 16, 8)];
+        part.custom.insert("sound".to_string(), Value::String("stone".to_string()));
+        part.custom.insert("slippery".to_string(), Value::Bool(false));
+
+        let mut item = TileProperties::default();
+        item.movement_cost = Some(0.5);
+        item.custom.insert("slippery".to_string(), Value::Bool(true));
+
+        let merged = part.merged(&item);
+        assert_eq!(merged.solid, Some(true));
+        assert_eq!(merged.movement_cost, Some(0.5));
+        assert_eq!(merged.collision, vec![rect(0, 0, 16, 8)]);
+        assert_eq!(merged.custom["sound"], Value::String("stone".to_string()));
+        assert_eq!(merged.custom["slippery"], Value::Bool(true));
+        assert!(TileProperties::default().merged(&TileProperties::default()).is_empty());
+    }
+
+    #[test]
+    fn bad_properties_are_rejected() {
+        let mut props = TileProperties::default();
+        props.collision = vec![rect(8, 8, 8, 8), rect(8, 8, 9, 8)];
+        props.movement_cost = Some(-1.0);
+
+        let errors = props.validate(Path::new("wall.json"), "morning_brick", [16, 16]);
+        let names: Vec<&str> = errors.iter()
+            .filter_map(|e| match *e {
+                Error::BadProperty { property, .. } => Some(property),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["collision", "movement_cost"]);
+
+        props.collision.pop();
+        props.movement_cost = Some(1.5);
+        assert!(props.validate(Path::new("wall.json"), "morning_brick", [16, 16]).is_empty());
+    }
+}
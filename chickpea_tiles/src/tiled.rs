@@ -0,0 +1,911 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use rustc_serialize::base64::FromBase64;
+use serde;
+use serde_json;
+use serde_json::value::Value;
+use xml::reader::{EventReader, XmlEvent};
+
+use image::{self, GenericImage};
+
+use {json_path, load, AtlasRect, AtlasOptions, Error, InputTileFormat, OutputTileFormat, TileProperties,
+     TileRect, TileSet, TileSetItem, TileSetResult, TileSetSource, TileSetSourceGroup, TileSetSourceItem,
+     TileSource};
+use map::{Cell, TileMap, FLIP_DIAGONAL, FLIP_HORIZONTAL, FLIP_VERTICAL};
+
+/// Every imported tile is an item of this format, with a single part.
+pub const TILED_OUTPUT_FORMAT: &'static str = "output_tile_formats/tiled_tile";
+pub const TILED_INPUT_FORMAT: &'static str = "input_tile_formats/tiled_tile";
+pub const TILED_PART: &'static str = "tile";
+
+const FLIPS: u32 = FLIP_HORIZONTAL | FLIP_VERTICAL | FLIP_DIAGONAL;
+
+/// A Tiled tileset, read from a TSX file or embedded in a map.
+#[derive(Clone, Debug)]
+pub struct Tileset {
+    pub name: String,
+    pub tile_size: [usize; 2],
+    pub columns: usize,
+    pub tile_count: usize,
+    /// The tileset's image, resolved against the file it was read from.
+    pub image: PathBuf,
+    /// The tiles Tiled has anything more to say about, by Tiled tile id.
+    pub tiles: BTreeMap<usize, TiledTile>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TiledTile {
+    /// The `id` property, which names the tile's item.
+    pub id: Option<String>,
+    pub properties: TileProperties,
+}
+
+impl Tileset {
+    /// The item id of a tile, its `id` property if it has one, otherwise the
+    /// tileset's name and the tile's Tiled id.
+    pub fn item_id(&self, tile: usize) -> String {
+        match self.tiles.get(&tile).and_then(|t| t.id.as_ref()) {
+            Some(id) => id.clone(),
+            None => format!("{}_{}", self.name, tile),
+        }
+    }
+
+    /// Every tile of the tileset as an item of `TILED_INPUT_FORMAT`.
+    pub fn group(&self, from: &str) -> TileSetSourceGroup {
+        let items = (0..self.tile_count)
+            .map(|tile| {
+                TileSetSourceItem {
+                    id: self.item_id(tile),
+                    loc: [tile % self.columns, tile / self.columns],
+                    duration_ms: None,
+                    frames: Vec::new(),
+                    properties: self.tiles.get(&tile).map_or(TileProperties::default(), |t| t.properties.clone()),
+                }
+            })
+            .collect();
+        TileSetSourceGroup {
+            from: from.to_string(),
+            fmt: TILED_INPUT_FORMAT.to_string(),
+            items: items,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TiledLayer {
+    pub name: String,
+    /// Global tile ids, flip bits included, row by row.
+    pub gids: Vec<u32>,
+}
+
+/// A Tiled map, with its external tilesets read in.
+#[derive(Clone, Debug)]
+pub struct TiledMap {
+    pub size: [usize; 2],
+    pub tile_size: [usize; 2],
+    /// The first global id of every tileset, in ascending order.
+    pub tilesets: Vec<(u32, Tileset)>,
+    pub layers: Vec<TiledLayer>,
+    path: PathBuf,
+}
+
+impl TiledMap {
+    /// Converts the map, with every tile referring to the item `import`
+    /// turns it into.
+    pub fn to_tile_map(&self) -> TileSetResult<TileMap> {
+        let mut map = TileMap::new(self.size, self.tile_size);
+        for layer in &self.layers {
+            let index = map.add_layer(&layer.name);
+            for (i, &gid) in layer.gids.iter().enumerate() {
+                let cell = match gid & !FLIPS {
+                    0 => Cell::empty(),
+                    id => {
+                        let item = try!(self.item_id(id));
+                        let palette_index = map.palette_index(TILED_OUTPUT_FORMAT, &item);
+                        Cell::new(palette_index, gid & FLIPS)
+                    }
+                };
+                map.layers[index].cells[i] = cell;
+            }
+        }
+        Ok(map)
+    }
+
+    fn item_id(&self, gid: u32) -> TileSetResult<String> {
+        let tileset = self.tilesets.iter().rev().find(|&&(first, _)| first <= gid);
+        match tileset {
+            Some(&(first, ref tileset)) if ((gid - first) as usize) < tileset.tile_count => {
+                Ok(tileset.item_id((gid - first) as usize))
+            }
+            _ => Err(bad(&self.path, format!("no tileset has global tile id {}", gid))),
+        }
+    }
+}
+
+fn bad<S: Into<String>>(path: &Path, reason: S) -> Error {
+    Error::BadTiledFile {
+        path: path.to_path_buf(),
+        reason: reason.into(),
+    }
+}
+
+// a parsed xml element, Tiled's files are small enough to read whole
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|a| a.0 == name).map(|a| &a.1[..])
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn children_named<'a>(&'a self, name: &'a str) -> Box<Iterator<Item = &'a Element> + 'a> {
+        Box::new(self.children.iter().filter(move |c| c.name == name))
+    }
+}
+
+fn parse_xml(path: &Path) -> TileSetResult<Element> {
+    let file = try!(File::open(path).map_err(|err| Error::IOError(path.to_path_buf(), err)));
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+    for event in EventReader::new(file) {
+        match try!(event.map_err(|err| Error::XmlError(path.to_path_buf(), err))) {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                stack.push(Element {
+                    name: name.local_name,
+                    attributes: attributes.into_iter().map(|a| (a.name.local_name, a.value)).collect(),
+                    children: Vec::new(),
+                    text: String::new(),
+                });
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = match stack.pop() {
+                    Some(element) => element,
+                    None => return Err(bad(path, "unbalanced xml")),
+                };
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => root = Some(element),
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+    }
+    root.ok_or_else(|| bad(path, "empty document"))
+}
+
+fn attr<T: FromStr>(path: &Path, element: &Element, name: &str) -> TileSetResult<T> {
+    match try!(opt_attr(path, element, name)) {
+        Some(value) => Ok(value),
+        None => Err(bad(path, format!("<{}> has no `{}` attribute", element.name, name))),
+    }
+}
+
+fn opt_attr<T: FromStr>(path: &Path, element: &Element, name: &str) -> TileSetResult<Option<T>> {
+    match element.attr(name) {
+        None => Ok(None),
+        Some(value) => {
+            value.parse()
+                .map(Some)
+                .map_err(|_| bad(path, format!("bad `{}` attribute on <{}>: {}", name, element.name, value)))
+        }
+    }
+}
+
+/// Reads a TSX tileset.
+pub fn read_tsx(path: &Path) -> TileSetResult<Tileset> {
+    let root = try!(parse_xml(path));
+    if root.name != "tileset" {
+        return Err(bad(path, format!("expected a <tileset>, found <{}>", root.name)));
+    }
+    parse_tileset(path, &root)
+}
+
+fn parse_tileset(path: &Path, element: &Element) -> TileSetResult<Tileset> {
+    let name: String = try!(attr(path, element, "name"));
+    let tile_size = [try!(attr(path, element, "tilewidth")), try!(attr(path, element, "tileheight"))];
+    let spacing = try!(opt_attr(path, element, "spacing")).unwrap_or(0usize);
+    let margin = try!(opt_attr(path, element, "margin")).unwrap_or(0usize);
+    if spacing != 0 || margin != 0 {
+        return Err(bad(path, format!("tileset `{}` has spacing or a margin", name)));
+    }
+    if tile_size[0] == 0 || tile_size[1] == 0 {
+        return Err(bad(path, format!("tileset `{}` has an empty tile size", name)));
+    }
+
+    let image = match element.child("image") {
+        Some(image) => image,
+        None => return Err(bad(path, format!("tileset `{}` isn't a single image", name))),
+    };
+    let source: String = try!(attr(path, image, "source"));
+    let image_size: [usize; 2] = [try!(attr(path, image, "width")), try!(attr(path, image, "height"))];
+
+    let columns = try!(opt_attr(path, element, "columns")).unwrap_or(image_size[0] / tile_size[0]);
+    let rows = image_size[1] / tile_size[1];
+    let tile_count = try!(opt_attr(path, element, "tilecount")).unwrap_or(columns * rows);
+    if columns == 0 || tile_count > columns * rows {
+        return Err(bad(path, format!("tileset `{}` has more tiles than its image", name)));
+    }
+
+    let mut tiles = BTreeMap::new();
+    for tile in element.children_named("tile") {
+        let id: usize = try!(attr(path, tile, "id"));
+        tiles.insert(id, try!(parse_tile(path, tile)));
+    }
+
+    Ok(Tileset {
+        name: name,
+        tile_size: tile_size,
+        columns: columns,
+        tile_count: tile_count,
+        image: path.parent().unwrap_or(Path::new("")).join(source),
+        tiles: tiles,
+    })
+}
+
+fn parse_tile(path: &Path, element: &Element) -> TileSetResult<TiledTile> {
+    let mut tile = TiledTile::default();
+    if let Some(properties) = element.child("properties") {
+        for property in properties.children_named("property") {
+            let name: String = try!(attr(path, property, "name"));
+            let value = match property.attr("value") {
+                Some(value) => value,
+                None => property.text.trim(),
+            };
+            let kind = property.attr("type").unwrap_or("string");
+            let bad_value = || bad(path, format!("bad value for property `{}`: {}", name, value));
+            match (&name[..], kind) {
+                ("id", _) => tile.id = Some(value.to_string()),
+                ("solid", _) => tile.properties.solid = Some(try!(value.parse().map_err(|_| bad_value()))),
+                ("movement_cost", _) => {
+                    tile.properties.movement_cost = Some(try!(value.parse().map_err(|_| bad_value())))
+                }
+                (_, "bool") => {
+                    let value = try!(value.parse().map_err(|_| bad_value()));
+                    tile.properties.custom.insert(name.clone(), Value::Bool(value));
+                }
+                (_, "int") => {
+                    let value = try!(value.parse().map_err(|_| bad_value()));
+                    tile.properties.custom.insert(name.clone(), Value::I64(value));
+                }
+                (_, "float") => {
+                    let value = try!(value.parse().map_err(|_| bad_value()));
+                    tile.properties.custom.insert(name.clone(), Value::F64(value));
+                }
+                _ => {
+                    tile.properties.custom.insert(name.clone(), Value::String(value.to_string()));
+                }
+            }
+        }
+    }
+
+    if let Some(group) = element.child("objectgroup") {
+        for object in group.children_named("object") {
+            let rect: [f64; 4] = [try!(opt_attr(path, object, "x")).unwrap_or(0.0),
+                                  try!(opt_attr(path, object, "y")).unwrap_or(0.0),
+                                  try!(opt_attr(path, object, "width")).unwrap_or(0.0),
+                                  try!(opt_attr(path, object, "height")).unwrap_or(0.0)];
+            if !object.children.is_empty() || rect.iter().any(|&n| n < 0.0) || rect[2] == 0.0 || rect[3] == 0.0 {
+                return Err(bad(path, "only rectangles are supported as collision objects"));
+            }
+            tile.properties.collision.push(TileRect {
+                x: rect[0].round() as usize,
+                y: rect[1].round() as usize,
+                w: rect[2].round() as usize,
+                h: rect[3].round() as usize,
+            });
+        }
+    }
+
+    Ok(tile)
+}
+
+/// Reads a TMX map and the tilesets it refers to.
+pub fn read_tmx(path: &Path) -> TileSetResult<TiledMap> {
+    let root = try!(parse_xml(path));
+    if root.name != "map" {
+        return Err(bad(path, format!("expected a <map>, found <{}>", root.name)));
+    }
+    if root.attr("orientation").unwrap_or("orthogonal") != "orthogonal" {
+        return Err(bad(path, "only orthogonal maps are supported"));
+    }
+
+    let size: [usize; 2] = [try!(attr(path, &root, "width")), try!(attr(path, &root, "height"))];
+    let tile_size: [usize; 2] = [try!(attr(path, &root, "tilewidth")), try!(attr(path, &root, "tileheight"))];
+
+    let mut tilesets = Vec::new();
+    for element in root.children_named("tileset") {
+        let first_gid: u32 = try!(attr(path, element, "firstgid"));
+        let tileset = match element.attr("source") {
+            Some(source) => try!(read_tsx(&path.parent().unwrap_or(Path::new("")).join(source))),
+            None => try!(parse_tileset(path, element)),
+        };
+        if tileset.tile_size != tile_size {
+            return Err(bad(path, format!("tileset `{}` doesn't match the map's tile size", tileset.name)));
+        }
+        tilesets.push((first_gid, tileset));
+    }
+    tilesets.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut layers = Vec::new();
+    for layer in root.children_named("layer") {
+        let name: String = try!(attr(path, layer, "name"));
+        let data = match layer.child("data") {
+            Some(data) => data,
+            None => return Err(bad(path, format!("layer `{}` has no data", name))),
+        };
+        let gids = try!(parse_data(path, data));
+        if gids.len() != size[0] * size[1] {
+            return Err(bad(path,
+                           format!("layer `{}` has {} tiles, expected {}",
+                                   name,
+                                   gids.len(),
+                                   size[0] * size[1])));
+        }
+        layers.push(TiledLayer {
+            name: name,
+            gids: gids,
+        });
+    }
+
+    Ok(TiledMap {
+        size: size,
+        tile_size: tile_size,
+        tilesets: tilesets,
+        layers: layers,
+        path: path.to_path_buf(),
+    })
+}
+
+fn parse_data(path: &Path, data: &Element) -> TileSetResult<Vec<u32>> {
+    match data.attr("encoding") {
+        None => {
+            data.children_named("tile")
+                .map(|tile| opt_attr(path, tile, "gid").map(|gid| gid.unwrap_or(0)))
+                .collect()
+        }
+        Some("csv") => {
+            data.text
+                .split(',')
+                .map(|gid| gid.trim())
+                .filter(|gid| !gid.is_empty())
+                .map(|gid| gid.parse().map_err(|_| bad(path, format!("bad tile id in layer data: {}", gid))))
+                .collect()
+        }
+        Some("base64") => {
+            let bytes = try!(data.text
+                .trim()
+                .from_base64()
+                .map_err(|err| bad(path, format!("bad base64 layer data: {}", err))));
+            let mut raw = Vec::new();
+            let read = match data.attr("compression") {
+                None => {
+                    raw = bytes;
+                    Ok(0)
+                }
+                Some("zlib") => ZlibDecoder::new(&bytes[..]).read_to_end(&mut raw),
+                Some("gzip") => GzDecoder::new(&bytes[..]).and_then(|mut d| d.read_to_end(&mut raw)),
+                Some(other) => return Err(bad(path, format!("unsupported layer compression: {}", other))),
+            };
+            try!(read.map_err(|err| bad(path, format!("couldn't decompress layer data: {}", err))));
+            if raw.len() % 4 != 0 {
+                return Err(bad(path, "layer data isn't a whole number of tile ids"));
+            }
+            Ok(raw.chunks(4)
+                .map(|b| b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+                .collect())
+        }
+        Some(other) => Err(bad(path, format!("unsupported layer encoding: {}", other))),
+    }
+}
+
+fn write_json<T: serde::Serialize>(path: PathBuf, value: &T) -> TileSetResult<()> {
+    let path = json_path(path);
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir).map_err(|err| Error::IOError(dir.to_path_buf(), err)));
+    }
+    let mut writer = try!(File::create(&path).map_err(|err| Error::IOError(path.clone(), err)));
+    serde_json::ser::to_writer(&mut writer, value).map_err(|err| Error::JsonError(path.clone(), err))
+}
+
+// `path` relative to the folder `base`, both of which must exist
+fn relative_to(path: &Path, base: &Path) -> TileSetResult<PathBuf> {
+    let path = try!(fs::canonicalize(path).map_err(|err| Error::IOError(path.to_path_buf(), err)));
+    let base = try!(fs::canonicalize(base).map_err(|err| Error::IOError(base.to_path_buf(), err)));
+    let common = path.components().zip(base.components()).take_while(|&(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    for component in path.components().skip(common) {
+        relative.push(component.as_os_str());
+    }
+    Ok(relative)
+}
+
+/// Writes what it takes to compile `tilesets` into `src_folder`: a tile
+/// source per tileset, the single tile formats, and a tile set source
+/// named `name` with every tile of every tileset. Returns the path of the
+/// tile set source, relative to `src_folder`.
+pub fn import(src_folder: &Path, name: &str, tilesets: &[&Tileset]) -> TileSetResult<PathBuf> {
+    let tile_size = match tilesets.first() {
+        Some(tileset) => tileset.tile_size,
+        None => return Err(bad(Path::new(name), "nothing to import")),
+    };
+    try!(fs::create_dir_all(src_folder).map_err(|err| Error::IOError(src_folder.to_path_buf(), err)));
+
+    let mut parts = BTreeMap::new();
+    parts.insert(TILED_PART.to_string(), 1);
+    try!(write_json(src_folder.join(TILED_OUTPUT_FORMAT),
+                    &OutputTileFormat {
+                        parts: parts,
+                        autotile: None,
+                    }));
+    let mut parts = BTreeMap::new();
+    parts.insert(TILED_PART.to_string(), vec![[0, 0]]);
+    try!(write_json(src_folder.join(TILED_INPUT_FORMAT),
+                    &InputTileFormat {
+                        fmt: TILED_OUTPUT_FORMAT.to_string(),
+                        parts: parts,
+                        composed: BTreeMap::new(),
+                        properties: BTreeMap::new(),
+                    }));
+
+    let mut groups = Vec::with_capacity(tilesets.len());
+    for tileset in tilesets {
+        let from = format!("tile_sources/tiled/{}", tileset.name);
+        let image_path = try!(relative_to(&tileset.image, src_folder));
+        try!(write_json(src_folder.join(&from),
+                        &TileSource {
+                            image_path: image_path.to_string_lossy().into_owned(),
+                            tile_size: tileset.tile_size,
+                        }));
+        groups.push(tileset.group(&from));
+    }
+
+    let tss_path = Path::new("tile_set_sources").join(name);
+    try!(write_json(src_folder.join(&tss_path),
+                    &TileSetSource {
+                        tile_size: tile_size,
+                        groups: groups,
+                        atlas: AtlasOptions::default(),
+                    }));
+    Ok(tss_path)
+}
+
+// a tile of an exported page
+struct ExportTile {
+    name: String,
+    part: String,
+    properties: TileProperties,
+    // tile ids and durations
+    animation: Vec<(usize, u32)>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn property_value(value: &Value) -> (&'static str, String) {
+    match *value {
+        Value::Bool(b) => ("bool", b.to_string()),
+        Value::I64(n) => ("int", n.to_string()),
+        Value::U64(n) => ("int", n.to_string()),
+        Value::F64(n) => ("float", n.to_string()),
+        Value::String(ref s) => ("string", s.clone()),
+        ref other => ("string", serde_json::ser::to_string(other).unwrap_or(String::new())),
+    }
+}
+
+// the tiles of one page, each atlas tile named after the first item part
+// found using it, in format, item and part order
+fn page_tiles(path: &Path, ts: &TileSet, page: usize, columns: usize) -> TileSetResult<BTreeMap<usize, ExportTile>> {
+    let grid_id = |rect: &AtlasRect| {
+        if rect.w != ts.tile_size[0] || rect.h != ts.tile_size[1] || rect.x % ts.tile_size[0] != 0 ||
+           rect.y % ts.tile_size[1] != 0 {
+            return Err(bad(path, format!("atlas tile at {},{} isn't on the tile grid", rect.x, rect.y)));
+        }
+        Ok(rect.y / ts.tile_size[1] * columns + rect.x / ts.tile_size[0])
+    };
+
+    let mut items = Vec::new();
+    let mut fmts: Vec<&String> = ts.fmts.keys().collect();
+    fmts.sort();
+    for fmt in fmts {
+        let mut ids: Vec<&String> = ts.fmts[fmt].keys().collect();
+        ids.sort();
+        items.extend(ids.into_iter().map(|id| (id, &ts.fmts[fmt][id])));
+    }
+
+    let name_of = |id: &String, item: &TileSetItem, part: &str, index: usize, frame: usize| {
+        let single = item.parts.len() == 1 && item.parts[0].rects.len() == 1;
+        let name = if single {
+            id.clone()
+        } else {
+            format!("{}/{}/{}", id, part, index)
+        };
+        if frame > 0 {
+            format!("{}@{}", name, frame)
+        } else {
+            name
+        }
+    };
+
+    // every item names its first frame before any later frame is named,
+    // as later frames are often some other item's tiles
+    let mut tiles = BTreeMap::new();
+    for &(id, item) in &items {
+        for part in &item.parts {
+            for (index, rect) in part.rects.iter().enumerate().filter(|&(_, r)| r.page == page) {
+                tiles.entry(try!(grid_id(rect))).or_insert(ExportTile {
+                    name: name_of(id, item, &part.name, index, 0),
+                    part: part.name.clone(),
+                    properties: part.properties.clone(),
+                    animation: Vec::new(),
+                });
+            }
+        }
+    }
+
+    for &(id, item) in &items {
+        for (frame, later) in item.frames.iter().enumerate().skip(1) {
+            for part in &later.parts {
+                for (index, rect) in part.rects.iter().enumerate().filter(|&(_, r)| r.page == page) {
+                    tiles.entry(try!(grid_id(rect))).or_insert(ExportTile {
+                        name: name_of(id, item, &part.name, index, frame),
+                        part: part.name.clone(),
+                        properties: part.properties.clone(),
+                        animation: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        // Tiled animates within a single tileset, so animations
+        // spanning pages are left out
+        if item.frames.len() < 2 {
+            continue;
+        }
+        for (p, part) in item.parts.iter().enumerate() {
+            for index in 0..part.rects.len() {
+                let mut animation = Vec::new();
+                for frame in &item.frames {
+                    match frame.parts.get(p).and_then(|fp| fp.rects.get(index)) {
+                        Some(rect) if rect.page == page => {
+                            animation.push((try!(grid_id(rect)), frame.duration_ms))
+                        }
+                        _ => break,
+                    }
+                }
+                if animation.len() == item.frames.len() {
+                    let first = animation[0].0;
+                    if let Some(tile) = tiles.get_mut(&first) {
+                        if tile.animation.is_empty() {
+                            tile.animation = animation;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(tiles)
+}
+
+fn write_tsx<W: Write>(w: &mut W,
+                       name: &str,
+                       tile_size: [usize; 2],
+                       image: &str,
+                       image_size: [usize; 2],
+                       tiles: &BTreeMap<usize, ExportTile>)
+                       -> io::Result<()> {
+    let columns = image_size[0] / tile_size[0];
+    let tile_count = columns * (image_size[1] / tile_size[1]);
+    try!(writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#));
+    try!(writeln!(w,
+                  r#"<tileset name="{}" tilewidth="{}" tileheight="{}" tilecount="{}" columns="{}">"#,
+                  escape(name),
+                  tile_size[0],
+                  tile_size[1],
+                  tile_count,
+                  columns));
+    try!(writeln!(w,
+                  r#" <image source="{}" width="{}" height="{}"/>"#,
+                  escape(image),
+                  image_size[0],
+                  image_size[1]));
+
+    for (id, tile) in tiles {
+        try!(writeln!(w, r#" <tile id="{}" type="{}">"#, id, escape(&tile.part)));
+        try!(writeln!(w, "  <properties>"));
+        try!(writeln!(w, r#"   <property name="id" value="{}"/>"#, escape(&tile.name)));
+        let props = &tile.properties;
+        if let Some(solid) = props.solid {
+            try!(writeln!(w, r#"   <property name="solid" type="bool" value="{}"/>"#, solid));
+        }
+        if let Some(cost) = props.movement_cost {
+            try!(writeln!(w, r#"   <property name="movement_cost" type="float" value="{}"/>"#, cost));
+        }
+        for (key, value) in &props.custom {
+            let (kind, value) = property_value(value);
+            try!(writeln!(w,
+                          r#"   <property name="{}" type="{}" value="{}"/>"#,
+                          escape(key),
+                          kind,
+                          escape(&value)));
+        }
+        try!(writeln!(w, "  </properties>"));
+
+        if !props.collision.is_empty() {
+            try!(writeln!(w, r#"  <objectgroup draworder="index">"#));
+            for (i, r) in props.collision.iter().enumerate() {
+                try!(writeln!(w,
+                              r#"   <object id="{}" x="{}" y="{}" width="{}" height="{}"/>"#,
+                              i + 1,
+                              r.x,
+                              r.y,
+                              r.w,
+                              r.h));
+            }
+            try!(writeln!(w, "  </objectgroup>"));
+        }
+
+        if !tile.animation.is_empty() {
+            try!(writeln!(w, "  <animation>"));
+            for &(frame, duration) in &tile.animation {
+                try!(writeln!(w, r#"   <frame tileid="{}" duration="{}"/>"#, frame, duration));
+            }
+            try!(writeln!(w, "  </animation>"));
+        }
+        try!(writeln!(w, " </tile>"));
+    }
+    writeln!(w, "</tileset>")
+}
+
+// the exported `id` properties leave out the format, so an id can only be
+// used by one format
+fn check_unique_ids(path: &Path, ts: &TileSet) -> TileSetResult<()> {
+    let mut fmts: Vec<&String> = ts.fmts.keys().collect();
+    fmts.sort();
+    let mut seen: BTreeMap<&String, &String> = BTreeMap::new();
+    for fmt in fmts {
+        for id in ts.fmts[fmt].keys() {
+            if let Some(first) = seen.insert(id, fmt) {
+                return Err(Error::AmbiguousTiledId {
+                    path: path.to_path_buf(),
+                    id: id.clone(),
+                    fmts: [first.clone(), fmt.clone()],
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a TSX tileset for every page of the compiled tile set at
+/// `ts_path`, next to the page it uses, and returns their paths. Every
+/// tile's `id` property names its item, followed by its part, index and
+/// frame when the item has more than a single tile, so item ids must not
+/// repeat across formats.
+pub fn export(ts_path: &Path) -> TileSetResult<Vec<PathBuf>> {
+    let ts_path = json_path(ts_path.to_path_buf());
+    let ts: TileSet = try!(load(ts_path.clone()));
+    try!(check_unique_ids(&ts_path, &ts));
+
+    let mut outputs = Vec::with_capacity(ts.image_paths.len());
+    for (page, image_path) in ts.image_paths.iter().enumerate() {
+        let page_path = ts_path.with_file_name(image_path);
+        let (w, h) = try!(image::open(&page_path).map_err(|err| Error::ImageError(page_path.clone(), err)))
+            .dimensions();
+        let image_size = [w as usize, h as usize];
+
+        let tsx_path = page_path.with_extension("tsx");
+        let tiles = try!(page_tiles(&tsx_path, &ts, page, image_size[0] / ts.tile_size[0]));
+        let name = Path::new(image_path).file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+
+        let mut writer = try!(File::create(&tsx_path).map_err(|err| Error::IOError(tsx_path.clone(), err)));
+        try!(write_tsx(&mut writer, &name, ts.tile_size, image_path, image_size, &tiles)
+            .map_err(|err| Error::IOError(tsx_path.clone(), err)));
+        outputs.push(tsx_path);
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use serde_json::value::Value;
+    use map::{FLIP_DIAGONAL, FLIP_HORIZONTAL, FLIP_VERTICAL};
+    use std::fs::File;
+    use {compile_tile_set, load, TileRect, TileSet};
+
+    #[test]
+    fn tsx_tilesets_are_read() {
+        let tileset = read_tsx(Path::new("test_data/src/tiled/autotiles.tsx")).expect("couldn't read tileset");
+        assert_eq!(tileset.name, "autotiles");
+        assert_eq!(tileset.tile_size, [16, 16]);
+        assert_eq!((tileset.columns, tileset.tile_count), (4, 12));
+        assert_eq!(tileset.image,
+                   Path::new("test_data/src/tiled/../raw_images/autotiles/a2.png").to_path_buf());
+
+        assert_eq!(tileset.item_id(0), "water_corner");
+        assert_eq!(tileset.item_id(1), "autotiles_1");
+        assert_eq!(tileset.tiles[&0].properties.solid, Some(true));
+
+        let props = &tileset.tiles[&5].properties;
+        assert_eq!(props.movement_cost, Some(2.5));
+        assert_eq!(props.custom["depth"], Value::I64(3));
+        assert_eq!(props.custom["sound"], Value::String("splash".to_string()));
+        assert_eq!(props.collision,
+                   vec![TileRect {
+                            x: 0,
+                            y: 8,
+                            w: 16,
+                            h: 8,
+                        }]);
+    }
+
+    #[test]
+    fn tmx_layers_decode_every_encoding() {
+        let tiled = read_tmx(Path::new("test_data/src/tiled/pond.tmx")).expect("couldn't read map");
+        assert_eq!(tiled.tilesets.len(), 1);
+        let map = tiled.to_tile_map().expect("couldn't convert map");
+        assert_eq!(map.size, [4, 3]);
+
+        let names: Vec<&str> = map.layers.iter().map(|l| &l.name[..]).collect();
+        assert_eq!(names, vec!["ground", "water", "decor"]);
+        let id = |layer: usize, pos: [usize; 2]| map.entry(map.get(layer, pos)).map(|e| e.id.clone());
+
+        // csv
+        assert_eq!(id(0, [1, 0]), Some("autotiles_1".to_string()));
+        assert_eq!(id(0, [3, 2]), Some("autotiles_11".to_string()));
+        // base64
+        assert_eq!(id(1, [0, 0]), None);
+        assert_eq!(id(1, [2, 0]), Some("autotiles_5".to_string()));
+        assert_eq!(map.get(1, [1, 1]).flips(), FLIP_HORIZONTAL);
+        assert_eq!(map.get(1, [2, 1]).flips(), FLIP_VERTICAL);
+        // base64 and zlib
+        assert_eq!(id(2, [0, 0]), Some("water_corner".to_string()));
+        assert_eq!(map.get(2, [0, 0]).flips(), FLIP_DIAGONAL);
+        assert_eq!(map.palette.len(), 12);
+    }
+
+    #[test]
+    fn imported_tilesets_compile() {
+        let tileset = read_tsx(Path::new("test_data/src/tiled/autotiles.tsx")).expect("couldn't read tileset");
+        let src = Path::new("test_data/target/tiled_src");
+        let tss_path = import(src, "autotiles", &[&tileset]).expect("import failed");
+        assert_eq!(tss_path, Path::new("tile_set_sources/autotiles").to_path_buf());
+
+        let stats = compile_tile_set(src, &tss_path, Path::new("test_data/target"), Path::new("tile_sets/tiled"))
+            .expect("compilation failed");
+        assert_eq!(stats.tiles, 12);
+
+        let ts: TileSet = load(Path::new("test_data/target/tile_sets/tiled").to_path_buf())
+            .expect("couldn't read compiled tile set");
+        let items = &ts.fmts[TILED_OUTPUT_FORMAT];
+        assert_eq!(items.len(), 12);
+        let deep = items["autotiles_5"].part(TILED_PART).expect("missing part");
+        assert_eq!(deep.properties.movement_cost, Some(2.5));
+        assert!(items.contains_key("water_corner"));
+    }
+
+    #[test]
+    fn exported_tilesets_read_back() {
+        compile_tile_set(Path::new("test_data/src"),
+                         Path::new("tile_set_sources/morning"),
+                         Path::new("test_data/target"),
+                         Path::new("tile_sets/morning_tsx"))
+            .expect("compilation failed");
+        let outputs = export(Path::new("test_data/target/tile_sets/morning_tsx")).expect("export failed");
+        assert_eq!(outputs,
+                   vec![Path::new("test_data/target/tile_sets/morning_tsx.0.tsx").to_path_buf()]);
+
+        let tileset = read_tsx(&outputs[0]).expect("couldn't read exported tileset");
+        assert_eq!(tileset.image,
+                   Path::new("test_data/target/tile_sets/morning_tsx.0.png").to_path_buf());
+        assert_eq!(tileset.tile_count, 256 * 128 / (16 * 16));
+
+        let find = |id: &str| {
+            tileset.tiles
+                .values()
+                .find(|t| t.id.as_ref().map_or(false, |i| i == id))
+                .expect("missing tile")
+        };
+        assert_eq!(find("morning_grass/numpad/4").properties.movement_cost, Some(1.5));
+        let wood = &find("morning_wood_wall/cross/0").properties;
+        assert_eq!(wood.solid, Some(true));
+        assert_eq!(wood.custom["flammable"], Value::Bool(true));
+    }
+
+    #[test]
+    fn animations_are_exported() {
+        compile_tile_set(Path::new("test_data/src"),
+                         Path::new("tile_set_sources/blob"),
+                         Path::new("test_data/target"),
+                         Path::new("tile_sets/blob_tsx"))
+            .expect("compilation failed");
+        let outputs = export(Path::new("test_data/target/tile_sets/blob_tsx")).expect("export failed");
+
+        let mut tsx = String::new();
+        File::open(&outputs[0]).and_then(|mut f| f.read_to_string(&mut tsx)).expect("couldn't read tsx");
+        assert_eq!(tsx.matches("<animation>").count(), 47);
+        assert!(tsx.contains(r#"<property name="id" value="blue_water/blob/0"/>"#));
+        assert!(tsx.contains(r#"duration="500"/>"#));
+    }
+
+    #[test]
+    fn later_frames_keep_their_own_names() {
+        compile_tile_set(Path::new("test_data/src"),
+                         Path::new("tile_set_sources/blob"),
+                         Path::new("test_data/target"),
+                         Path::new("tile_sets/blob_names"))
+            .expect("compilation failed");
+        let outputs = export(Path::new("test_data/target/tile_sets/blob_names")).expect("export failed");
+
+        // blue_water's second frame is purple_water, which keeps its tiles
+        let mut tsx = String::new();
+        File::open(&outputs[0]).and_then(|mut f| f.read_to_string(&mut tsx)).expect("couldn't read tsx");
+        assert!(tsx.contains(r#"<property name="id" value="purple_water/blob/0"/>"#));
+        assert!(!tsx.contains("@1"));
+    }
+
+    #[test]
+    fn ids_shared_by_formats_are_refused() {
+        compile_tile_set(Path::new("test_data/src"),
+                         Path::new("tile_set_sources/morning"),
+                         Path::new("test_data/target"),
+                         Path::new("tile_sets/morning_shared_ids"))
+            .expect("compilation failed");
+        let mut ts: TileSet = load(Path::new("test_data/target/tile_sets/morning_shared_ids").to_path_buf())
+            .expect("couldn't read tile set");
+        assert!(check_unique_ids(Path::new("shared.json"), &ts).is_ok());
+
+        let stone = ts.fmts["output_tile_formats/floor"]["morning_stone"].clone();
+        ts.fmts.get_mut("output_tile_formats/wall").unwrap().insert(String::from("morning_stone"), stone);
+        match check_unique_ids(Path::new("shared.json"), &ts) {
+            Err(Error::AmbiguousTiledId { ref id, ref fmts, .. }) => {
+                assert_eq!(id, "morning_stone");
+                assert_eq!(fmts, &[String::from("output_tile_formats/floor"),
+                                   String::from("output_tile_formats/wall")]);
+            }
+            other => panic!("expected an ambiguous id error, got {:?}", other),
+        }
+    }
+}
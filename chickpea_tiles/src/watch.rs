@@ -0,0 +1,141 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use {dependencies, find_tile_set_sources, json_path};
+
+// modification time and length, the length catches edits made within the
+// timestamp resolution of the file system
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    fs::metadata(path).ok().and_then(|m| m.modified().ok().map(|t| (t, m.len())))
+}
+
+/// Watches a source folder by polling, and works out which tile set sources
+/// need recompiling because they, or any file they depend on, changed.
+pub struct Watcher {
+    src_folder: PathBuf,
+    // every tile set source, with the stamps of its dependencies as of the
+    // last time it was reported
+    sources: BTreeMap<PathBuf, BTreeMap<PathBuf, Stamp>>,
+}
+
+impl Watcher {
+    pub fn new(src_folder: &Path) -> Watcher {
+        Watcher {
+            src_folder: src_folder.to_path_buf(),
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// The tile set sources (relative to the source folder) that were added
+    /// or affected by a change since the last poll. The first poll reports
+    /// every source.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let current = find_tile_set_sources(&self.src_folder).unwrap_or(Vec::new());
+        let removed: Vec<PathBuf> = self.sources
+            .keys()
+            .filter(|source| !current.contains(*source))
+            .cloned()
+            .collect();
+        for source in removed {
+            self.sources.remove(&source);
+        }
+
+        let mut affected = Vec::new();
+        for source in current {
+            let changed = match self.sources.get(&source) {
+                None => true,
+                Some(stamps) => stamps.iter().any(|(path, s)| stamp(path) != *s),
+            };
+            if changed {
+                let stamps = self.stamps(&source);
+                self.sources.insert(source.clone(), stamps);
+                affected.push(source);
+            }
+        }
+        affected
+    }
+
+    fn stamps(&self, source: &Path) -> BTreeMap<PathBuf, Stamp> {
+        // a source that can't be read yet still gets watched itself, so
+        // fixing it triggers another compile
+        let deps = dependencies(&self.src_folder, source)
+            .unwrap_or(vec![json_path(self.src_folder.join(source))]);
+        deps.into_iter()
+            .map(|path| {
+                let s = stamp(&path);
+                (path, s)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).expect("couldn't create folder");
+        for entry in fs::read_dir(from).expect("couldn't read folder") {
+            let path = entry.expect("couldn't read entry").path();
+            let dest = to.join(path.file_name().unwrap());
+            if path.is_dir() {
+                copy_dir(&path, &dest);
+            } else {
+                fs::copy(&path, &dest).expect("couldn't copy file");
+            }
+        }
+    }
+
+    #[test]
+    fn changes_trigger_affected_sources() {
+        let src = Path::new("test_data/target/watch_src");
+        let _ = fs::remove_dir_all(src);
+        copy_dir(Path::new("test_data/src"), src);
+
+        let blob = PathBuf::from("tile_set_sources/blob");
+        let morning = PathBuf::from("tile_set_sources/morning");
+        let evening = PathBuf::from("tile_set_sources/evening");
+
+        let mut watcher = Watcher::new(src);
+        assert_eq!(watcher.poll(), vec![blob.clone(), morning.clone()]);
+        assert!(watcher.poll().is_empty());
+
+        {
+            let mut f = OpenOptions::new()
+                .append(true)
+                .open(src.join("output_tile_formats/wall.json"))
+                .expect("couldn't open output format");
+            f.write_all(b"\n").expect("couldn't touch output format");
+        }
+        assert_eq!(watcher.poll(), vec![morning.clone()]);
+
+        fs::copy(src.join("tile_set_sources/morning.json"),
+                 src.join("tile_set_sources/evening.json"))
+            .expect("couldn't add tile set source");
+        assert_eq!(watcher.poll(), vec![evening.clone()]);
+        assert!(watcher.poll().is_empty());
+    }
+}
@@ -0,0 +1,20 @@
+<?xml version="1.0" encoding="UTF-8"?>
+<tileset name="autotiles" tilewidth="16" tileheight="16" tilecount="12" columns="4">
+ <image source="../raw_images/autotiles/a2.png" width="64" height="48"/>
+ <tile id="0">
+  <properties>
+   <property name="id" value="water_corner"/>
+   <property name="solid" type="bool" value="true"/>
+  </properties>
+ </tile>
+ <tile id="5">
+  <properties>
+   <property name="movement_cost" type="float" value="2.5"/>
+   <property name="depth" type="int" value="3"/>
+   <property name="sound" value="splash"/>
+  </properties>
+  <objectgroup draworder="index">
+   <object id="1" x="0" y="8" width="16" height="8"/>
+  </objectgroup>
+ </tile>
+</tileset>
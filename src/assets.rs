@@ -0,0 +1,69 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chickpea_tiles::{LoadedTileSet, TileSetResult};
+
+/// A compiled tile set loaded from disk at runtime, which notices when the
+/// compiler rewrites it.
+pub struct TileSetAsset {
+    path: PathBuf,
+    stamp: Option<SystemTime>,
+    pub tile_set: LoadedTileSet,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl TileSetAsset {
+    pub fn load(path: &Path) -> TileSetResult<TileSetAsset> {
+        // the compiler writes the tile set after its pages, so its stamp
+        // is enough to tell when a new build is complete
+        let stamp = modified(path);
+        let tile_set = try!(LoadedTileSet::open(path));
+
+        Ok(TileSetAsset {
+            path: path.to_path_buf(),
+            stamp: stamp,
+            tile_set: tile_set,
+        })
+    }
+
+    /// Reloads the tile set if it was recompiled since it was last loaded,
+    /// returning whether it did. On failure the old tile set is kept.
+    pub fn reload_if_changed(&mut self) -> TileSetResult<bool> {
+        let stamp = modified(&self.path);
+        if stamp == self.stamp {
+            return Ok(false);
+        }
+        match TileSetAsset::load(&self.path) {
+            Ok(asset) => {
+                *self = asset;
+                Ok(true)
+            }
+            Err(err) => {
+                // don't retry until the file changes again, going by the
+                // stamp from before the attempt in case a build just ended
+                self.stamp = stamp;
+                Err(err)
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+pub const MIN_ZOOM: u32 = 1;
+pub const MAX_ZOOM: u32 = 8;
+
+/// How quickly `follow` closes in on its target, per second.
+pub const DEFAULT_FOLLOW_RATE: f32 = 8.0;
+
+/// An orthographic camera over the world, in pixels with y pointing down.
+/// Zoom is a whole number of screen pixels per world pixel, and the view
+/// is snapped to screen pixels, so tiles are always drawn pixel perfect.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Camera2D {
+    /// The world position at the center of the screen.
+    pub position: [f32; 2],
+    pub follow_rate: f32,
+    zoom: u32,
+    viewport: [u32; 2],
+    // the world rect the view stays inside
+    bounds: Option<([f32; 2], [f32; 2])>,
+}
+
+impl Camera2D {
+    pub fn new(viewport: [u32; 2]) -> Camera2D {
+        Camera2D {
+            position: [0.0, 0.0],
+            follow_rate: DEFAULT_FOLLOW_RATE,
+            zoom: MIN_ZOOM,
+            viewport: viewport,
+            bounds: None,
+        }
+    }
+
+    pub fn zoom(&self) -> u32 {
+        self.zoom
+    }
+
+    pub fn set_zoom(&mut self, zoom: u32) {
+        self.zoom = zoom.max(MIN_ZOOM).min(MAX_ZOOM);
+        self.clamp();
+    }
+
+    pub fn zoom_in(&mut self) {
+        let zoom = self.zoom + 1;
+        self.set_zoom(zoom);
+    }
+
+    pub fn zoom_out(&mut self) {
+        let zoom = self.zoom - 1;
+        self.set_zoom(zoom);
+    }
+
+    pub fn viewport(&self) -> [u32; 2] {
+        self.viewport
+    }
+
+    /// Changes the screen size, for when the window is resized.
+    pub fn set_viewport(&mut self, viewport: [u32; 2]) {
+        self.viewport = viewport;
+        self.clamp();
+    }
+
+    /// Keeps the view inside the world rect from `min` to `max`, a map's
+    /// pixel size for instance.
+    pub fn set_bounds(&mut self, min: [f32; 2], max: [f32; 2]) {
+        self.bounds = Some((min, max));
+        self.clamp();
+    }
+
+    /// How much of the world fits on the screen.
+    pub fn view_size(&self) -> [f32; 2] {
+        [self.viewport[0] as f32 / self.zoom as f32, self.viewport[1] as f32 / self.zoom as f32]
+    }
+
+    pub fn pan(&mut self, delta: [f32; 2]) {
+        self.position[0] += delta[0];
+        self.position[1] += delta[1];
+        self.clamp();
+    }
+
+    pub fn look_at(&mut self, target: [f32; 2]) {
+        self.position = target;
+        self.clamp();
+    }
+
+    /// Moves towards `target`, `dt` seconds after the last call. The rate
+    /// doesn't depend on how often it's called.
+    pub fn follow(&mut self, target: [f32; 2], dt: f32) {
+        let t = 1.0 - (-self.follow_rate * dt).exp();
+        self.position[0] += (target[0] - self.position[0]) * t;
+        self.position[1] += (target[1] - self.position[1]) * t;
+        self.clamp();
+    }
+
+    fn clamp(&mut self) {
+        let (min, max) = match self.bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let view = self.view_size();
+        for axis in 0..2 {
+            let half = view[axis] / 2.0;
+            self.position[axis] = if max[axis] - min[axis] <= view[axis] {
+                // the whole axis fits, so center it
+                (min[axis] + max[axis]) / 2.0
+            } else {
+                self.position[axis].max(min[axis] + half).min(max[axis] - half)
+            };
+        }
+    }
+
+    /// The world rect on screen, from its top left to its bottom right.
+    /// The top left always lands on a screen pixel.
+    pub fn visible_rect(&self) -> ([f32; 2], [f32; 2]) {
+        let view = self.view_size();
+        let zoom = self.zoom as f32;
+        let mut min = [0.0; 2];
+        let mut max = [0.0; 2];
+        for axis in 0..2 {
+            min[axis] = ((self.position[axis] - view[axis] / 2.0) * zoom).round() / zoom;
+            max[axis] = min[axis] + view[axis];
+        }
+        (min, max)
+    }
+
+    /// The projection and view matrix of the vertex shader, column major.
+    pub fn matrix(&self) -> [[f32; 4]; 4] {
+        let (min, max) = self.visible_rect();
+        let w = max[0] - min[0];
+        let h = max[1] - min[1];
+        [[2.0 / w, 0.0, 0.0, 0.0],
+         [0.0, -2.0 / h, 0.0, 0.0],
+         [0.0, 0.0, 1.0, 0.0],
+         [-(max[0] + min[0]) / w, (max[1] + min[1]) / h, 0.0, 1.0]]
+    }
+
+    /// Screen pixels, from the top left of the screen.
+    pub fn world_to_screen(&self, world: [f32; 2]) -> [f32; 2] {
+        let (min, _) = self.visible_rect();
+        let zoom = self.zoom as f32;
+        [(world[0] - min[0]) * zoom, (world[1] - min[1]) * zoom]
+    }
+
+    pub fn screen_to_world(&self, screen: [f32; 2]) -> [f32; 2] {
+        let (min, _) = self.visible_rect();
+        let zoom = self.zoom as f32;
+        [min[0] + screen[0] / zoom, min[1] + screen[1] / zoom]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(m: &[[f32; 4]; 4], p: [f32; 2]) -> [f32; 2] {
+        [m[0][0] * p[0] + m[1][0] * p[1] + m[3][0], m[0][1] * p[0] + m[1][1] * p[1] + m[3][1]]
+    }
+
+    fn close(a: [f32; 2], b: [f32; 2]) -> bool {
+        (a[0] - b[0]).abs() < 1e-4 && (a[1] - b[1]).abs() < 1e-4
+    }
+
+    #[test]
+    fn matrix_maps_the_view_onto_the_screen() {
+        let mut camera = Camera2D::new([640, 480]);
+        camera.set_zoom(2);
+        camera.look_at([200.0, 100.0]);
+        let (min, max) = camera.visible_rect();
+        assert_eq!((min, max), ([40.0, -20.0], [360.0, 220.0]));
+
+        let m = camera.matrix();
+        assert!(close(project(&m, min), [-1.0, 1.0]));
+        assert!(close(project(&m, max), [1.0, -1.0]));
+        assert!(close(project(&m, [200.0, 100.0]), [0.0, 0.0]));
+    }
+
+    #[test]
+    fn world_and_screen_round_trip() {
+        let mut camera = Camera2D::new([320, 240]);
+        camera.set_zoom(2);
+        camera.look_at([64.0, 48.0]);
+        assert_eq!(camera.world_to_screen([64.0, 48.0]), [160.0, 120.0]);
+        assert_eq!(camera.screen_to_world([0.0, 0.0]), camera.visible_rect().0);
+
+        let world = [70.5, 12.25];
+        camera.set_zoom(3);
+        assert!(close(camera.screen_to_world(camera.world_to_screen(world)), world));
+    }
+
+    #[test]
+    fn views_snap_to_screen_pixels() {
+        let mut camera = Camera2D::new([301, 200]);
+        for &zoom in &[1, 2, 4, 8] {
+            camera.set_zoom(zoom);
+            camera.look_at([100.37, 33.9]);
+            let (min, _) = camera.visible_rect();
+            let z = zoom as f32;
+            assert_eq!((min[0] * z).fract(), 0.0);
+            assert_eq!((min[1] * z).fract(), 0.0);
+            // so a tile's corner lands on a whole screen pixel
+            let corner = camera.world_to_screen([16.0, 32.0]);
+            assert_eq!((corner[0].fract(), corner[1].fract()), (0.0, 0.0));
+        }
+        camera.set_zoom(100);
+        assert_eq!(camera.zoom(), MAX_ZOOM);
+        camera.set_zoom(0);
+        camera.zoom_out();
+        assert_eq!(camera.zoom(), MIN_ZOOM);
+    }
+
+    #[test]
+    fn follow_eases_towards_its_target() {
+        let mut camera = Camera2D::new([100, 100]);
+        let mut last = 100.0;
+        for _ in 0..60 {
+            camera.follow([100.0, 0.0], 1.0 / 60.0);
+            let left = 100.0 - camera.position[0];
+            assert!(left > 0.0 && left < last);
+            last = left;
+        }
+        assert!(last < 0.1);
+
+        // one long step ends up about where many short ones do
+        let mut once = Camera2D::new([100, 100]);
+        once.follow([100.0, 0.0], 1.0);
+        assert!((once.position[0] - camera.position[0]).abs() < 0.1);
+    }
+
+    #[test]
+    fn bounds_keep_the_map_on_screen() {
+        let mut camera = Camera2D::new([320, 240]);
+        camera.set_bounds([0.0, 0.0], [640.0, 160.0]);
+        camera.look_at([0.0, 0.0]);
+        // the map is wider than the screen, but not as tall
+        assert_eq!(camera.position, [160.0, 80.0]);
+        camera.pan([10000.0, 50.0]);
+        assert_eq!(camera.position, [480.0, 80.0]);
+
+        camera.set_zoom(2);
+        camera.look_at([0.0, 0.0]);
+        assert_eq!(camera.position, [80.0, 60.0]);
+        assert_eq!(camera.visible_rect(), ([0.0, 0.0], [160.0, 120.0]));
+    }
+}
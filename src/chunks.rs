@@ -0,0 +1,453 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chickpea_tiles::{LoadedTileSet, TileId, TileMap, UvRect};
+use chickpea_tiles::autotile;
+use chickpea_tiles::map::{Cell, FLIP_DIAGONAL, FLIP_HORIZONTAL, FLIP_VERTICAL};
+
+/// Width and height of a chunk, in cells.
+pub const CHUNK_SIZE: usize = 16;
+
+pub const NO_TINT: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+/// The color a layer's tiles are multiplied by, layers past the end of
+/// `tints` aren't tinted.
+pub fn layer_tint(tints: &[[f32; 4]], layer: usize) -> [f32; 4] {
+    tints.get(layer).cloned().unwrap_or(NO_TINT)
+}
+
+/// One tile quad, as the tile shader draws it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileInstance {
+    /// Top left corner, in world pixels.
+    pub world_pos: [f32; 2],
+    /// The uv of the quad's top left and bottom right corners, after flips.
+    pub tex_min: [f32; 2],
+    pub tex_max: [f32; 2],
+    /// 1 when the tile is drawn transposed, ie. flipped diagonally.
+    pub transposed: f32,
+}
+
+impl TileInstance {
+    /// The instance of a tile at `world_pos` with a cell's flip bits applied.
+    pub fn new(world_pos: [f32; 2], uv: &UvRect, flips: u32) -> TileInstance {
+        let diagonal = flips & FLIP_DIAGONAL != 0;
+        // with a diagonal flip the quad's x samples the texture's y, so the
+        // other flips apply to the opposite texture axes
+        let (flip_x, flip_y) = if diagonal {
+            (flips & FLIP_VERTICAL != 0, flips & FLIP_HORIZONTAL != 0)
+        } else {
+            (flips & FLIP_HORIZONTAL != 0, flips & FLIP_VERTICAL != 0)
+        };
+        let mut tex_min = uv.min;
+        let mut tex_max = uv.max;
+        if flip_x {
+            tex_min[0] = uv.max[0];
+            tex_max[0] = uv.min[0];
+        }
+        if flip_y {
+            tex_min[1] = uv.max[1];
+            tex_max[1] = uv.min[1];
+        }
+        TileInstance {
+            world_pos: world_pos,
+            tex_min: tex_min,
+            tex_max: tex_max,
+            transposed: if diagonal { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+/// Identifies a chunk of one layer of a map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkKey {
+    pub layer: usize,
+    pub chunk: [usize; 2],
+}
+
+/// The quads of a chunk, grouped by atlas page.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChunkMesh {
+    pub pages: Vec<(usize, Vec<TileInstance>)>,
+    /// Whether any tile is animated, so the chunk changes over time.
+    pub animated: bool,
+    // every animated tile in the chunk, with the frame it was built with
+    frames: Vec<(TileId, usize)>,
+}
+
+impl ChunkMesh {
+    fn push(&mut self, page: usize, instance: TileInstance) {
+        if let Some(&mut (_, ref mut instances)) = self.pages.iter_mut().find(|&&mut (p, _)| p == page) {
+            instances.push(instance);
+            return;
+        }
+        self.pages.push((page, vec![instance]));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.iter().map(|&(_, ref instances)| instances.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn frames_changed(&self, ts: &LoadedTileSet, time_ms: u64) -> bool {
+        self.frames.iter().any(|&(tile, frame)| ts.frame_at(tile, time_ms) != frame)
+    }
+}
+
+/// A map split into chunks whose quads are rebuilt only when their cells,
+/// or their neighbors, change.
+pub struct ChunkedMap {
+    map: TileMap,
+    // the tile of every palette entry, none when the tile set lacks it
+    palette: Vec<Option<TileId>>,
+    num_chunks: [usize; 2],
+    meshes: Vec<ChunkMesh>,
+    dirty: Vec<bool>,
+}
+
+fn resolve_palette(map: &TileMap, ts: &LoadedTileSet) -> Vec<Option<TileId>> {
+    map.palette
+        .iter()
+        .map(|entry| ts.format(&entry.fmt).and_then(|format| ts.tile(format, &entry.id)))
+        .collect()
+}
+
+impl ChunkedMap {
+    pub fn new(map: TileMap, ts: &LoadedTileSet) -> ChunkedMap {
+        let palette = resolve_palette(&map, ts);
+        let num_chunks = [(map.size[0] + CHUNK_SIZE - 1) / CHUNK_SIZE,
+                          (map.size[1] + CHUNK_SIZE - 1) / CHUNK_SIZE];
+        let len = map.layers.len() * num_chunks[0] * num_chunks[1];
+        ChunkedMap {
+            map: map,
+            palette: palette,
+            num_chunks: num_chunks,
+            meshes: vec![ChunkMesh::default(); len],
+            dirty: vec![true; len],
+        }
+    }
+
+    pub fn map(&self) -> &TileMap {
+        &self.map
+    }
+
+    /// Looks up the tiles again, after the tile set was reloaded.
+    pub fn set_tile_set(&mut self, ts: &LoadedTileSet) {
+        self.palette = resolve_palette(&self.map, ts);
+        for dirty in &mut self.dirty {
+            *dirty = true;
+        }
+    }
+
+    fn index(&self, key: ChunkKey) -> usize {
+        (key.layer * self.num_chunks[1] + key.chunk[1]) * self.num_chunks[0] + key.chunk[0]
+    }
+
+    /// Changes a cell, returning false if it's outside the map.
+    pub fn set(&mut self, layer: usize, pos: [usize; 2], cell: Cell) -> bool {
+        if !self.map.set(layer, pos, cell) {
+            return false;
+        }
+        // autotiles depend on their neighbors, which may be in other chunks
+        for &(_, offset) in &autotile::OFFSETS {
+            self.mark_dirty(layer, [pos[0] as i32 + offset[0], pos[1] as i32 + offset[1]]);
+        }
+        self.mark_dirty(layer, [pos[0] as i32, pos[1] as i32]);
+        true
+    }
+
+    fn mark_dirty(&mut self, layer: usize, pos: [i32; 2]) {
+        if pos[0] < 0 || pos[1] < 0 || pos[0] as usize >= self.map.size[0] ||
+           pos[1] as usize >= self.map.size[1] {
+            return;
+        }
+        let key = ChunkKey {
+            layer: layer,
+            chunk: [pos[0] as usize / CHUNK_SIZE, pos[1] as usize / CHUNK_SIZE],
+        };
+        let index = self.index(key);
+        self.dirty[index] = true;
+    }
+
+    /// Rebuilds the chunks that changed, along with the animated chunks
+    /// whose tiles moved on to another frame, returning which ones were
+    /// rebuilt.
+    pub fn update(&mut self, ts: &LoadedTileSet, time_ms: u64) -> Vec<ChunkKey> {
+        let mut rebuilt = Vec::new();
+        for layer in 0..self.map.layers.len() {
+            for y in 0..self.num_chunks[1] {
+                for x in 0..self.num_chunks[0] {
+                    let key = ChunkKey {
+                        layer: layer,
+                        chunk: [x, y],
+                    };
+                    let index = self.index(key);
+                    if !self.dirty[index] && !self.meshes[index].frames_changed(ts, time_ms) {
+                        continue;
+                    }
+                    let mesh = self.build(ts, key, time_ms);
+                    self.meshes[index] = mesh;
+                    self.dirty[index] = false;
+                    rebuilt.push(key);
+                }
+            }
+        }
+        rebuilt
+    }
+
+    pub fn mesh(&self, key: ChunkKey) -> &ChunkMesh {
+        &self.meshes[self.index(key)]
+    }
+
+    /// The tile of a cell, with the part and index its neighbors pick.
+    pub fn cell_tile(&self, ts: &LoadedTileSet, layer: usize, pos: [usize; 2]) -> Option<(TileId, usize, usize)> {
+        let tile = match self.tile_at(layer, pos[0] as i32, pos[1] as i32) {
+            Some(tile) => tile,
+            None => return None,
+        };
+        match ts.autotile(ts.tile_format(tile)) {
+            Some(table) => {
+                let mask = autotile::neighbor_mask(|offset| {
+                    let x = pos[0] as i32 + offset[0];
+                    let y = pos[1] as i32 + offset[1];
+                    // past the edges the map is assumed to carry on
+                    !self.in_map(x, y) || self.tile_at(layer, x, y) == Some(tile)
+                });
+                let resolved = table.resolve(mask);
+                Some((tile, resolved[0], resolved[1]))
+            }
+            None => Some((tile, 0, 0)),
+        }
+    }
+
+    fn in_map(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.map.size[0] && (y as usize) < self.map.size[1]
+    }
+
+    fn tile_at(&self, layer: usize, x: i32, y: i32) -> Option<TileId> {
+        if !self.in_map(x, y) {
+            return None;
+        }
+        self.map
+            .get(layer, [x as usize, y as usize])
+            .item()
+            .and_then(|item| self.palette.get(item).and_then(|tile| *tile))
+    }
+
+    fn build(&self, ts: &LoadedTileSet, key: ChunkKey, time_ms: u64) -> ChunkMesh {
+        let mut mesh = ChunkMesh::default();
+        let x0 = key.chunk[0] * CHUNK_SIZE;
+        let y0 = key.chunk[1] * CHUNK_SIZE;
+        for y in y0..(y0 + CHUNK_SIZE).min(self.map.size[1]) {
+            for x in x0..(x0 + CHUNK_SIZE).min(self.map.size[0]) {
+                let (tile, part, index) = match self.cell_tile(ts, key.layer, [x, y]) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                let frame = ts.frame_at(tile, time_ms);
+                if ts.num_frames(tile) > 1 {
+                    mesh.animated = true;
+                    if !mesh.frames.iter().any(|&(t, _)| t == tile) {
+                        mesh.frames.push((tile, frame));
+                    }
+                }
+                if let Some(uv) = ts.frame_uv(tile, frame, part, index) {
+                    let world_pos = [(x * self.map.tile_size[0]) as f32,
+                                     (y * self.map.tile_size[1]) as f32];
+                    let flips = self.map.get(key.layer, [x, y]).flips();
+                    mesh.push(uv.page, TileInstance::new(world_pos, uv, flips));
+                }
+            }
+        }
+        mesh
+    }
+
+    /// The chunks overlapping the world rect from `min` to `max`, in pixels.
+    pub fn visible(&self, min: [f32; 2], max: [f32; 2]) -> Vec<[usize; 2]> {
+        let mut ranges = [(0, 0); 2];
+        for axis in 0..2 {
+            let chunk_px = (CHUNK_SIZE * self.map.tile_size[axis]) as f32;
+            // clamped to the map, whose last chunk may be partly outside it
+            let extent = (self.map.size[axis] * self.map.tile_size[axis]) as f32;
+            let lo_px = min[axis].max(0.0);
+            let hi_px = max[axis].min(extent);
+            if lo_px >= hi_px {
+                return Vec::new();
+            }
+            let lo = (lo_px / chunk_px).floor() as usize;
+            let hi = ((hi_px / chunk_px).ceil() as usize).min(self.num_chunks[axis]);
+            ranges[axis] = (lo, hi);
+        }
+        let mut chunks = Vec::new();
+        for y in ranges[1].0..ranges[1].1 {
+            for x in ranges[0].0..ranges[0].1 {
+                chunks.push([x, y]);
+            }
+        }
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chickpea_tiles::{TileMap, UvRect};
+    use chickpea_tiles::autotile::EAST;
+    use chickpea_tiles::map::{Cell, FLIP_DIAGONAL, FLIP_HORIZONTAL};
+    use test_data::{blob, morning};
+
+    const FLOOR: &'static str = "output_tile_formats/floor";
+
+    fn grass_map(size: [usize; 2]) -> TileMap {
+        let mut map = TileMap::new(size, [16, 16]);
+        let layer = map.add_layer("ground");
+        let grass = map.palette_index(FLOOR, "morning_grass");
+        for y in 0..size[1] {
+            for x in 0..size[0] {
+                map.set(layer, [x, y], Cell::new(grass, 0));
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn chunks_cover_the_map() {
+        let ts = morning("chunks_cover");
+        let mut chunked = ChunkedMap::new(grass_map([40, 20]), &ts);
+        assert_eq!(chunked.update(&ts, 0).len(), 6);
+
+        let corner = chunked.mesh(ChunkKey {
+            layer: 0,
+            chunk: [2, 1],
+        });
+        assert_eq!(corner.len(), 8 * 4);
+        assert!(!corner.animated);
+        assert_eq!(corner.pages[0].1[0].world_pos, [32.0 * 16.0, 16.0 * 16.0]);
+
+        // nothing changed, so nothing is rebuilt
+        assert!(chunked.update(&ts, 100).is_empty());
+    }
+
+    #[test]
+    fn edits_only_dirty_their_neighborhood() {
+        let ts = morning("chunks_dirty");
+        let mut map = grass_map([40, 20]);
+        let stone = map.palette_index(FLOOR, "morning_stone");
+        let mut chunked = ChunkedMap::new(map, &ts);
+        chunked.update(&ts, 0);
+
+        assert!(chunked.set(0, [20, 5], Cell::new(stone, 0)));
+        let rebuilt: Vec<[usize; 2]> = chunked.update(&ts, 0).iter().map(|k| k.chunk).collect();
+        assert_eq!(rebuilt, vec![[1, 0]]);
+
+        // on a chunk's edge the neighboring chunk changes as well
+        assert!(chunked.set(0, [16, 15], Cell::new(stone, 0)));
+        let rebuilt: Vec<[usize; 2]> = chunked.update(&ts, 0).iter().map(|k| k.chunk).collect();
+        assert_eq!(rebuilt, vec![[0, 0], [1, 0], [0, 1], [1, 1]]);
+
+        assert!(!chunked.set(0, [40, 0], Cell::new(stone, 0)));
+        assert!(chunked.update(&ts, 0).is_empty());
+    }
+
+    #[test]
+    fn autotiles_follow_their_neighbors() {
+        let ts = morning("chunks_autotile");
+        let mut map = TileMap::new([8, 8], [16, 16]);
+        map.add_layer("ground");
+        let grass = map.palette_index(FLOOR, "morning_grass");
+        let mut chunked = ChunkedMap::new(map, &ts);
+        let tile = ts.tile(ts.format(FLOOR).unwrap(), "morning_grass").unwrap();
+        let table = ts.autotile(ts.format(FLOOR).unwrap()).unwrap().clone();
+
+        chunked.set(0, [3, 3], Cell::new(grass, 0));
+        chunked.update(&ts, 0);
+        let alone = table.resolve(0);
+        assert_eq!(chunked.cell_tile(&ts, 0, [3, 3]), Some((tile, alone[0], alone[1])));
+        let uv = ts.uv(tile, alone[0], alone[1]).unwrap();
+        let key = ChunkKey {
+            layer: 0,
+            chunk: [0, 0],
+        };
+        assert_eq!(chunked.mesh(key).pages[0].1[0].tex_min, uv.min);
+
+        chunked.set(0, [4, 3], Cell::new(grass, 0));
+        chunked.update(&ts, 0);
+        let joined = table.resolve(EAST);
+        assert_eq!(chunked.cell_tile(&ts, 0, [3, 3]), Some((tile, joined[0], joined[1])));
+        assert_eq!(chunked.mesh(key).len(), 2);
+    }
+
+    #[test]
+    fn animated_chunks_rebuild_on_new_frames() {
+        let ts = blob("chunks_animated");
+        let mut map = TileMap::new([20, 4], [16, 16]);
+        let layer = map.add_layer("water");
+        let water = map.palette_index("output_tile_formats/blob47", "blue_water");
+        map.set(layer, [2, 2], Cell::new(water, 0));
+        let mut chunked = ChunkedMap::new(map, &ts);
+        assert_eq!(chunked.update(&ts, 0).len(), 2);
+        let key = ChunkKey {
+            layer: 0,
+            chunk: [0, 0],
+        };
+        assert!(chunked.mesh(key).animated);
+        let first = chunked.mesh(key).pages[0].1[0];
+
+        // the first frame lasts 250ms and the second 500ms
+        assert!(chunked.update(&ts, 100).is_empty());
+        assert_eq!(chunked.update(&ts, 250), vec![key]);
+        assert!(chunked.mesh(key).pages[0].1[0] != first);
+        assert!(chunked.update(&ts, 700).is_empty());
+        assert_eq!(chunked.update(&ts, 750), vec![key]);
+        assert_eq!(chunked.mesh(key).pages[0].1[0], first);
+    }
+
+    #[test]
+    fn culling_keeps_overlapping_chunks() {
+        let ts = morning("chunks_culling");
+        let chunked = ChunkedMap::new(grass_map([40, 20]), &ts);
+        // chunks are 256 pixels wide
+        assert_eq!(chunked.visible([0.0, 0.0], [100.0, 100.0]), vec![[0, 0]]);
+        assert_eq!(chunked.visible([250.0, -50.0], [300.0, 10.0]),
+                   vec![[0, 0], [1, 0]]);
+        assert_eq!(chunked.visible([-100.0, 0.0], [10000.0, 300.0]).len(), 6);
+        assert!(chunked.visible([700.0, 0.0], [900.0, 100.0]).is_empty());
+        assert!(chunked.visible([-300.0, -300.0], [-10.0, -10.0]).is_empty());
+    }
+
+    #[test]
+    fn flips_swap_texture_corners() {
+        let uv = UvRect {
+            page: 0,
+            min: [0.25, 0.5],
+            max: [0.5, 0.75],
+        };
+        let plain = TileInstance::new([0.0, 0.0], &uv, 0);
+        assert_eq!((plain.tex_min, plain.tex_max, plain.transposed),
+                   ([0.25, 0.5], [0.5, 0.75], 0.0));
+
+        let mirrored = TileInstance::new([0.0, 0.0], &uv, FLIP_HORIZONTAL);
+        assert_eq!((mirrored.tex_min, mirrored.tex_max), ([0.5, 0.5], [0.25, 0.75]));
+
+        // transposed, a horizontal flip runs along the texture's y
+        let rotated = TileInstance::new([0.0, 0.0], &uv, FLIP_DIAGONAL | FLIP_HORIZONTAL);
+        assert_eq!((rotated.tex_min, rotated.tex_max, rotated.transposed),
+                   ([0.25, 0.75], [0.5, 0.5], 1.0));
+    }
+}
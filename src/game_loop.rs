@@ -0,0 +1,185 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use time;
+
+/// A source of monotonic time, in nanoseconds.
+pub trait Clock {
+    fn now_ns(&mut self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ns(&mut self) -> u64 {
+        time::precise_time_ns()
+    }
+}
+
+/// A clock that only moves when told to, for stepping loops in tests.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    pub ns: u64,
+}
+
+impl ManualClock {
+    pub fn advance(&mut self, ns: u64) {
+        self.ns += ns;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ns(&mut self) -> u64 {
+        self.ns
+    }
+}
+
+/// Splits elapsed time into fixed ticks. Whatever is left over is the
+/// fraction of a tick that rendering should interpolate by.
+#[derive(Clone, Debug)]
+pub struct FixedStep {
+    step_ns: u64,
+    // at most this many ticks are run per frame, the rest is dropped so a
+    // long stall doesn't turn into a spiral of ever longer frames
+    max_steps: u32,
+    accumulator: u64,
+    previous: Option<u64>,
+    ticks: u64,
+}
+
+impl FixedStep {
+    pub fn new(step_ns: u64, max_steps: u32) -> FixedStep {
+        FixedStep {
+            step_ns: step_ns,
+            max_steps: max_steps,
+            accumulator: 0,
+            previous: None,
+            ticks: 0,
+        }
+    }
+
+    /// Catches up with the clock, returning how many ticks to run. The
+    /// first call only starts the clock.
+    pub fn advance<C: Clock>(&mut self, clock: &mut C) -> u32 {
+        let now = clock.now_ns();
+        if let Some(previous) = self.previous {
+            self.accumulator += now.saturating_sub(previous);
+        }
+        self.previous = Some(now);
+
+        let steps = self.accumulator / self.step_ns;
+        let steps = if steps > self.max_steps as u64 {
+            self.accumulator %= self.step_ns;
+            self.max_steps
+        } else {
+            self.accumulator -= steps * self.step_ns;
+            steps as u32
+        };
+        self.ticks += steps as u64;
+        steps
+    }
+
+    /// How far between the last tick and the next one the clock is, from
+    /// 0 to 1.
+    pub fn alpha(&self) -> f32 {
+        self.accumulator as f32 / self.step_ns as f32
+    }
+
+    /// How many ticks ran in total.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Simulated time, including the fraction of the coming tick.
+    pub fn time_ns(&self) -> u64 {
+        self.ticks * self.step_ns + self.accumulator
+    }
+
+    /// How long until the next tick is due.
+    pub fn until_next_ns(&self) -> u64 {
+        self.step_ns - self.accumulator
+    }
+}
+
+/// Interpolates between the state of the last two ticks.
+pub fn lerp(a: [f32; 2], b: [f32; 2], alpha: f32) -> [f32; 2] {
+    [a[0] + (b[0] - a[0]) * alpha, a[1] + (b[1] - a[1]) * alpha]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP: u64 = 10_000_000;
+
+    #[test]
+    fn leftover_time_becomes_alpha() {
+        let mut clock = ManualClock::default();
+        let mut stepper = FixedStep::new(STEP, 5);
+        assert_eq!(stepper.advance(&mut clock), 0);
+
+        clock.advance(STEP * 5 / 2);
+        assert_eq!(stepper.advance(&mut clock), 2);
+        assert_eq!(stepper.alpha(), 0.5);
+        assert_eq!(stepper.until_next_ns(), STEP / 2);
+
+        clock.advance(STEP / 2);
+        assert_eq!(stepper.advance(&mut clock), 1);
+        assert_eq!(stepper.alpha(), 0.0);
+        assert_eq!(stepper.ticks(), 3);
+        assert_eq!(stepper.time_ns(), 3 * STEP);
+    }
+
+    #[test]
+    fn catching_up_is_capped() {
+        let mut clock = ManualClock::default();
+        let mut stepper = FixedStep::new(STEP, 5);
+        stepper.advance(&mut clock);
+
+        clock.advance(STEP * 100 + STEP / 4);
+        assert_eq!(stepper.advance(&mut clock), 5);
+        assert_eq!(stepper.alpha(), 0.25);
+        // the dropped time isn't made up for later
+        assert_eq!(stepper.advance(&mut clock), 0);
+        assert_eq!(stepper.ticks(), 5);
+    }
+
+    #[test]
+    fn ticks_dont_depend_on_frame_times() {
+        let frames: [&[u64]; 3] = [&[STEP; 12],
+                                   &[STEP / 4; 48],
+                                   &[STEP * 7 / 4, STEP / 2, STEP * 3, STEP * 9 / 4, STEP * 9 / 2]];
+        for frame_times in &frames {
+            let mut clock = ManualClock::default();
+            let mut stepper = FixedStep::new(STEP, 5);
+            stepper.advance(&mut clock);
+            let mut ticks = 0;
+            for &ns in frame_times.iter() {
+                clock.advance(ns);
+                ticks += stepper.advance(&mut clock);
+            }
+            assert_eq!(ticks, 12);
+            assert_eq!(stepper.time_ns(), 12 * STEP);
+        }
+    }
+
+    #[test]
+    fn lerp_blends_positions() {
+        assert_eq!(lerp([0.0, 8.0], [4.0, 0.0], 0.0), [0.0, 8.0]);
+        assert_eq!(lerp([0.0, 8.0], [4.0, 0.0], 0.25), [1.0, 6.0]);
+        assert_eq!(lerp([0.0, 8.0], [4.0, 0.0], 1.0), [4.0, 0.0]);
+    }
+}
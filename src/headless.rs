@@ -0,0 +1,177 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use chickpea_tiles::LoadedTileSet;
+use serde_json;
+use serde_json::value::Value;
+
+use input::{Input, InputEvent};
+use world::World;
+
+/// Input to feed the game at given ticks, written as a JSON array of steps
+/// like `{ "tick": 3, "press": "Right" }`. Besides "press" and "release" a
+/// step can move the mouse with `"mouse": [x, y]` or scroll with `"wheel"`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    // sorted by tick
+    steps: Vec<(u64, InputEvent)>,
+}
+
+fn parse_step(step: &Value) -> Result<(u64, InputEvent), String> {
+    let tick = match step.find("tick").and_then(|v| v.as_u64()) {
+        Some(tick) => tick,
+        None => return Err(String::from("missing tick")),
+    };
+    let event = if let Some(name) = step.find("press").and_then(|v| v.as_string()) {
+        InputEvent::press(name)
+    } else if let Some(name) = step.find("release").and_then(|v| v.as_string()) {
+        InputEvent::release(name)
+    } else if let Some(pos) = step.find("mouse").and_then(|v| v.as_array()) {
+        let coords: Vec<f32> = pos.iter().filter_map(|v| v.as_f64()).map(|v| v as f32).collect();
+        if coords.len() != 2 || pos.len() != 2 {
+            return Err(String::from("mouse needs an [x, y] position"));
+        }
+        InputEvent::MouseMoved([coords[0], coords[1]])
+    } else if let Some(lines) = step.find("wheel").and_then(|v| v.as_f64()) {
+        InputEvent::MouseWheel(lines as f32)
+    } else {
+        return Err(String::from("expected press, release, mouse or wheel"));
+    };
+    Ok((tick, event))
+}
+
+impl Script {
+    pub fn from_json(json: &str) -> Result<Script, String> {
+        let value: Value = try!(serde_json::de::from_str(json).map_err(|err| err.to_string()));
+        let items = match value.as_array() {
+            Some(items) => items,
+            None => return Err(String::from("expected an array of steps")),
+        };
+        let mut steps = Vec::with_capacity(items.len());
+        for (i, item) in items.iter().enumerate() {
+            steps.push(try!(parse_step(item).map_err(|err| format!("step {}: {}", i, err))));
+        }
+        // stable, so steps on the same tick keep their order
+        steps.sort_by_key(|&(tick, _)| tick);
+        Ok(Script { steps: steps })
+    }
+
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let mut json = String::new();
+        try!(File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut json))
+            .map_err(|err| format!("{}: {}", path.display(), err)));
+        Script::from_json(&json).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn events_at(&self, tick: u64) -> Vec<&InputEvent> {
+        self.steps
+            .iter()
+            .filter(|&&(t, _)| t == tick)
+            .map(|&(_, ref event)| event)
+            .collect()
+    }
+}
+
+/// Runs the world for `ticks` fixed ticks, feeding it the script's input
+/// as if it was typed in. No clock is involved, so runs are repeatable.
+pub fn run(world: &mut World, ts: &LoadedTileSet, input: &mut Input, script: &Script, ticks: u64) {
+    for _ in 0..ticks {
+        for event in script.events_at(world.tick) {
+            input.handle(event);
+        }
+        let actions = input.tick();
+        world.update(&actions, ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chickpea_tiles::{LoadedTileSet, TileMap};
+    use chickpea_tiles::map::Cell;
+    use camera::Camera2D;
+    use input::{Input, InputEvent, Keymap};
+    use serde_json;
+    use test_data::morning;
+    use world::World;
+
+    const WALK: &'static str = r#"[
+        { "tick": 0, "press": "Down" },
+        { "tick": 1, "release": "Down" },
+        { "tick": 4, "press": "Right" },
+        { "tick": 20, "release": "Right" },
+        { "tick": 20, "press": "Return" },
+        { "tick": 21, "release": "Return" }
+    ]"#;
+
+    #[test]
+    fn scripts_parse_in_tick_order() {
+        let script = Script::from_json(r#"[
+            { "tick": 5, "release": "Z" },
+            { "tick": 2, "press": "Z" },
+            { "tick": 5, "mouse": [3, 4.5] },
+            { "tick": 6, "wheel": -1 }
+        ]"#)
+            .expect("script should parse");
+        assert_eq!(script.events_at(2), vec![&InputEvent::press("Z")]);
+        assert_eq!(script.events_at(5),
+                   vec![&InputEvent::release("Z"), &InputEvent::MouseMoved([3.0, 4.5])]);
+        assert_eq!(script.events_at(6), vec![&InputEvent::MouseWheel(-1.0)]);
+        assert!(script.events_at(3).is_empty());
+
+        assert!(Script::from_json(r#"{ "tick": 0, "press": "Z" }"#).is_err());
+        assert!(Script::from_json(r#"[{ "press": "Z" }]"#).is_err());
+        assert!(Script::from_json(r#"[{ "tick": 0, "jump": "Z" }]"#).is_err());
+        assert!(Script::from_json(r#"[{ "tick": 0, "mouse": [1] }]"#).is_err());
+    }
+
+    fn field(ts: &LoadedTileSet) -> World {
+        let mut map = TileMap::new([12, 12], [16, 16]);
+        let ground = map.add_layer("ground");
+        let grass = map.palette_index("output_tile_formats/floor", "morning_grass");
+        for y in 0..12 {
+            for x in 0..12 {
+                map.set(ground, [x, y], Cell::new(grass, 0));
+            }
+        }
+        World::new(map, ts, Camera2D::new([96, 96]), 1.0 / 60.0)
+    }
+
+    #[test]
+    fn scripted_runs_are_repeatable() {
+        let ts = morning("headless_runs");
+        let script = Script::from_json(WALK).expect("script should parse");
+
+        let mut dumps = Vec::new();
+        for _ in 0..2 {
+            let mut world = field(&ts);
+            let mut input = Input::new(Keymap::default());
+            run(&mut world, &ts, &mut input, &script, 30);
+            assert_eq!(world.tick, 30);
+            // one step down, then one every 8 ticks for 16 ticks to the right
+            assert_eq!(world.player, [8, 7]);
+            assert_eq!(world.events.len(), 1);
+            assert_eq!(world.events[0].pos, [9, 7]);
+            dumps.push(serde_json::ser::to_string(&world.dump()).unwrap());
+        }
+        assert_eq!(dumps[0], dumps[1]);
+    }
+}
@@ -0,0 +1,358 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde_json;
+
+const DEFAULT_KEYMAP: &'static str = include_str!("keymap.json");
+
+const NUM_ACTIONS: usize = 13;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    Confirm,
+    Cancel,
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    /// Pans the camera along with the mouse while held.
+    PanDrag,
+    ZoomIn,
+    ZoomOut,
+}
+
+pub const ALL_ACTIONS: [Action; NUM_ACTIONS] = [Action::MoveUp,
+                                                Action::MoveDown,
+                                                Action::MoveLeft,
+                                                Action::MoveRight,
+                                                Action::Confirm,
+                                                Action::Cancel,
+                                                Action::PanUp,
+                                                Action::PanDown,
+                                                Action::PanLeft,
+                                                Action::PanRight,
+                                                Action::PanDrag,
+                                                Action::ZoomIn,
+                                                Action::ZoomOut];
+
+impl Action {
+    /// The action's name in keymaps.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::MoveUp => "move_up",
+            Action::MoveDown => "move_down",
+            Action::MoveLeft => "move_left",
+            Action::MoveRight => "move_right",
+            Action::Confirm => "confirm",
+            Action::Cancel => "cancel",
+            Action::PanUp => "pan_up",
+            Action::PanDown => "pan_down",
+            Action::PanLeft => "pan_left",
+            Action::PanRight => "pan_right",
+            Action::PanDrag => "pan_drag",
+            Action::ZoomIn => "zoom_in",
+            Action::ZoomOut => "zoom_out",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        ALL_ACTIONS.iter().cloned().find(|action| action.name() == name)
+    }
+}
+
+/// Raw input, independent of the windowing library. Keys are named after
+/// glutin's `VirtualKeyCode` variants ("A", "Left", "Return", ...) and
+/// mouse buttons are "MouseLeft", "MouseRight" and "MouseMiddle".
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    Button { name: String, pressed: bool },
+    /// The cursor's position, in screen pixels.
+    MouseMoved([f32; 2]),
+    /// Lines scrolled, positive away from the user.
+    MouseWheel(f32),
+    /// The window lost focus, so releases may never arrive.
+    FocusLost,
+}
+
+impl InputEvent {
+    pub fn press(name: &str) -> InputEvent {
+        InputEvent::Button {
+            name: String::from(name),
+            pressed: true,
+        }
+    }
+
+    pub fn release(name: &str) -> InputEvent {
+        InputEvent::Button {
+            name: String::from(name),
+            pressed: false,
+        }
+    }
+}
+
+/// Which buttons trigger every action.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    bindings: Vec<Vec<String>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut keymap = Keymap { bindings: vec![Vec::new(); NUM_ACTIONS] };
+        keymap.apply_json(DEFAULT_KEYMAP).expect("default keymap is invalid");
+        keymap
+    }
+}
+
+impl Keymap {
+    /// The default keymap with the actions of a JSON object rebound, the
+    /// object maps action names to lists of button names.
+    pub fn from_json(json: &str) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        try!(keymap.apply_json(json));
+        Ok(keymap)
+    }
+
+    pub fn load(path: &Path) -> Result<Keymap, String> {
+        let mut json = String::new();
+        try!(File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut json))
+            .map_err(|err| format!("{}: {}", path.display(), err)));
+        Keymap::from_json(&json).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    fn apply_json(&mut self, json: &str) -> Result<(), String> {
+        let bindings: BTreeMap<String, Vec<String>> = try!(serde_json::de::from_str(json)
+            .map_err(|err| err.to_string()));
+        for (name, buttons) in bindings {
+            match Action::from_name(&name) {
+                Some(action) => self.bind(action, buttons),
+                None => return Err(format!("unknown action {:?}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the buttons of an action.
+    pub fn bind(&mut self, action: Action, buttons: Vec<String>) {
+        self.bindings[action as usize] = buttons;
+    }
+
+    pub fn buttons(&self, action: Action) -> &[String] {
+        &self.bindings[action as usize]
+    }
+}
+
+/// What happened to the actions over one fixed tick.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ActionState {
+    down: [bool; NUM_ACTIONS],
+    pressed: [bool; NUM_ACTIONS],
+    released: [bool; NUM_ACTIONS],
+    /// How far the mouse was dragged while `PanDrag` was held, in screen pixels.
+    pub drag: [f32; 2],
+    pub wheel: f32,
+    pub mouse: [f32; 2],
+}
+
+impl ActionState {
+    /// Whether the action is held at the end of the tick.
+    pub fn is_down(&self, action: Action) -> bool {
+        self.down[action as usize]
+    }
+
+    /// Whether the action started during the tick, even if it was let go
+    /// before the tick ended.
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.pressed[action as usize]
+    }
+
+    pub fn just_released(&self, action: Action) -> bool {
+        self.released[action as usize]
+    }
+
+    /// -1, 0 or 1 along each axis, from four held actions.
+    pub fn direction(&self, up: Action, down: Action, left: Action, right: Action) -> [i32; 2] {
+        let axis = |neg: Action, pos: Action| self.is_down(pos) as i32 - self.is_down(neg) as i32;
+        [axis(left, right), axis(up, down)]
+    }
+
+    pub fn movement(&self) -> [i32; 2] {
+        self.direction(Action::MoveUp, Action::MoveDown, Action::MoveLeft, Action::MoveRight)
+    }
+
+    pub fn panning(&self) -> [i32; 2] {
+        self.direction(Action::PanUp, Action::PanDown, Action::PanLeft, Action::PanRight)
+    }
+}
+
+/// Turns input events into actions, one `ActionState` per fixed tick.
+pub struct Input {
+    pub keymap: Keymap,
+    held: HashSet<String>,
+    mouse: Option<[f32; 2]>,
+    state: ActionState,
+}
+
+impl Input {
+    pub fn new(keymap: Keymap) -> Input {
+        Input {
+            keymap: keymap,
+            held: HashSet::new(),
+            mouse: None,
+            state: ActionState::default(),
+        }
+    }
+
+    fn action_held(&self, action: Action) -> bool {
+        self.keymap.buttons(action).iter().any(|button| self.held.contains(button))
+    }
+
+    fn update_actions(&mut self) {
+        for &action in &ALL_ACTIONS {
+            let i = action as usize;
+            let down = self.action_held(action);
+            if down && !self.state.down[i] {
+                self.state.pressed[i] = true;
+            } else if !down && self.state.down[i] {
+                self.state.released[i] = true;
+            }
+            self.state.down[i] = down;
+        }
+    }
+
+    pub fn handle(&mut self, event: &InputEvent) {
+        match *event {
+            InputEvent::Button { ref name, pressed } => {
+                // key repeats press again without releasing, which changes nothing
+                if pressed {
+                    self.held.insert(name.clone());
+                } else {
+                    self.held.remove(name);
+                }
+                self.update_actions();
+            }
+            InputEvent::MouseMoved(pos) => {
+                if let Some(last) = self.mouse {
+                    if self.state.is_down(Action::PanDrag) {
+                        self.state.drag[0] += pos[0] - last[0];
+                        self.state.drag[1] += pos[1] - last[1];
+                    }
+                }
+                self.mouse = Some(pos);
+                self.state.mouse = pos;
+            }
+            InputEvent::MouseWheel(lines) => self.state.wheel += lines,
+            InputEvent::FocusLost => {
+                self.held.clear();
+                self.update_actions();
+            }
+        }
+    }
+
+    /// Ends the current tick, returning what happened during it.
+    pub fn tick(&mut self) -> ActionState {
+        let state = self.state.clone();
+        self.state.pressed = [false; NUM_ACTIONS];
+        self.state.released = [false; NUM_ACTIONS];
+        self.state.drag = [0.0, 0.0];
+        self.state.wheel = 0.0;
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(input: &mut Input, events: &[InputEvent]) -> ActionState {
+        for event in events {
+            input.handle(event);
+        }
+        input.tick()
+    }
+
+    #[test]
+    fn actions_follow_buttons_across_ticks() {
+        let mut input = Input::new(Keymap::default());
+        let first = run(&mut input, &[InputEvent::press("Return")]);
+        assert!(first.is_down(Action::Confirm) && first.just_pressed(Action::Confirm));
+
+        // held, and a key repeat doesn't press it again
+        let second = run(&mut input, &[InputEvent::press("Return")]);
+        assert!(second.is_down(Action::Confirm) && !second.just_pressed(Action::Confirm));
+
+        let third = run(&mut input, &[InputEvent::release("Return")]);
+        assert!(!third.is_down(Action::Confirm) && third.just_released(Action::Confirm));
+        assert_eq!(run(&mut input, &[]), ActionState::default());
+    }
+
+    #[test]
+    fn taps_within_a_tick_are_kept() {
+        let mut input = Input::new(Keymap::default());
+        let state = run(&mut input, &[InputEvent::press("Escape"), InputEvent::release("Escape")]);
+        assert!(!state.is_down(Action::Cancel));
+        assert!(state.just_pressed(Action::Cancel) && state.just_released(Action::Cancel));
+    }
+
+    #[test]
+    fn any_bound_button_holds_an_action() {
+        let mut input = Input::new(Keymap::default());
+        let state = run(&mut input,
+                        &[InputEvent::press("Left"), InputEvent::press("A"), InputEvent::press("W")]);
+        assert_eq!(state.movement(), [-1, -1]);
+        let state = run(&mut input, &[InputEvent::release("Left"), InputEvent::press("D")]);
+        assert_eq!(state.movement(), [0, -1]);
+        assert!(!state.just_released(Action::MoveLeft));
+
+        let state = run(&mut input, &[InputEvent::FocusLost]);
+        assert_eq!(state.movement(), [0, 0]);
+        assert!(state.just_released(Action::MoveLeft) && state.just_released(Action::MoveUp));
+    }
+
+    #[test]
+    fn keymaps_rebind_actions() {
+        let keymap = Keymap::from_json(r#"{ "confirm": ["E"], "pan_drag": ["MouseLeft"] }"#)
+            .expect("keymap should parse");
+        assert_eq!(keymap.buttons(Action::Confirm), &[String::from("E")]);
+        assert_eq!(keymap.buttons(Action::Cancel), Keymap::default().buttons(Action::Cancel));
+        assert!(Keymap::from_json(r#"{ "jump": ["Space"] }"#).is_err());
+        assert!(Keymap::from_json(r#"{ "confirm": "E" }"#).is_err());
+
+        let mut input = Input::new(keymap);
+        let state = run(&mut input,
+                        &[InputEvent::press("Return"),
+                          InputEvent::MouseMoved([10.0, 10.0]),
+                          InputEvent::press("MouseLeft"),
+                          InputEvent::MouseMoved([14.0, 7.0]),
+                          InputEvent::MouseMoved([20.0, 5.0]),
+                          InputEvent::MouseWheel(-1.0)]);
+        assert!(!state.is_down(Action::Confirm));
+        assert_eq!(state.drag, [10.0, -5.0]);
+        assert_eq!(state.mouse, [20.0, 5.0]);
+        assert_eq!(state.wheel, -1.0);
+        assert_eq!(run(&mut input, &[]).drag, [0.0, 0.0]);
+    }
+}
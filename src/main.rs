@@ -16,144 +16,355 @@
 
 #[macro_use]
 extern crate glium;
+extern crate chickpea_tiles;
 extern crate image;
+extern crate serde_json;
 extern crate time;
 
-use std::io::Cursor;
+mod assets;
+mod camera;
+mod chunks;
+mod game_loop;
+mod headless;
+mod input;
+mod renderer;
+mod software;
+#[cfg(test)]
+mod test_data;
+mod world;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
 use glium::glutin;
 use glium::Surface;
-use glium::draw_parameters::DrawParameters;
-use glium::index::PrimitiveType;
+use glium::backend::Facade;
+use glium::texture::{RawImage2d, SrgbTexture2d};
 use std::time::Duration;
 use std::thread;
 
-#[derive(Copy, Clone)]
-struct Vertex {
-    position: [f32; 2],
-    color: [f32; 3],
-    tex_coords: [f32; 2],
+use chickpea_tiles::{LoadedTileSet, TileMap, TileSet};
+use chickpea_tiles::map::Cell;
+
+use assets::TileSetAsset;
+use camera::Camera2D;
+use game_loop::{lerp, Clock, FixedStep, SystemClock};
+use headless::Script;
+use input::{Input, InputEvent, Keymap};
+use renderer::TileRenderer;
+use world::World;
+
+const DEFAULT_TILE_SET: &'static str = "chickpea_tiles/test_data/target/tile_sets/morning.json";
+const RELOAD_CHECK_NS: u64 = 500_000_000;
+const FIXED_TIME_STEP: u64 = 16_666_667;
+const MAX_CATCH_UP_STEPS: u32 = 5;
+const ZOOM: u32 = 2;
+const KEYMAP_PATH: &'static str = "keymap.json";
+const DEFAULT_HEADLESS_TICKS: u64 = 600;
+// the screen the camera pretends to have when there's no window
+const HEADLESS_VIEWPORT: [u32; 2] = [640, 480];
+
+const USAGE: &'static str = "\
+usage: chickpea [options] [tile_set [map]]
+
+Runs the game on the given compiled tile set and map, by default the
+morning test tile set and a generated map. Keys are bound by keymap.json
+in the current folder, if there is one.
+
+options:
+    --headless        run without a window, printing the world's state as
+                      JSON once done
+    --ticks <n>       how many fixed ticks to run headless (default 600)
+    --script <path>   input to feed the game headless, a JSON array of
+                      steps like { \"tick\": 3, \"press\": \"Right\" }
+    --preview <png>   render what the camera sees once the headless ticks
+                      ran to a PNG, without a GPU; implies --headless
+    -h, --help        print this message";
+
+struct Options {
+    tile_set: PathBuf,
+    map: Option<PathBuf>,
+    headless: bool,
+    ticks: u64,
+    script: Option<PathBuf>,
+    preview: Option<PathBuf>,
+}
+
+fn upload_pages<F: Facade>(display: &F, asset: &TileSetAsset) -> Vec<SrgbTexture2d> {
+    asset.tile_set
+        .pages
+        .iter()
+        .map(|page| {
+            // uploaded top row first, to match the tile set's uv rects
+            let image = RawImage2d::from_raw_rgba(page.clone().into_raw(), page.dimensions());
+            SrgbTexture2d::new(display, image).expect("texture creation failed")
+        })
+        .collect()
 }
 
-implement_vertex!(Vertex, position, color, tex_coords);
+fn input_event(event: &glutin::Event) -> Option<InputEvent> {
+    use glium::glutin::{ElementState, Event, MouseButton, MouseScrollDelta};
 
-#[derive(Copy, Clone)]
-struct Attr {
-    world_pos: [f32; 2],
+    match *event {
+        Event::KeyboardInput(state, _, Some(key)) => {
+            Some(InputEvent::Button {
+                name: format!("{:?}", key),
+                pressed: state == ElementState::Pressed,
+            })
+        }
+        Event::MouseInput(state, button) => {
+            let name = match button {
+                MouseButton::Left => String::from("MouseLeft"),
+                MouseButton::Right => String::from("MouseRight"),
+                MouseButton::Middle => String::from("MouseMiddle"),
+                MouseButton::Other(n) => format!("Mouse{}", n),
+            };
+            Some(InputEvent::Button {
+                name: name,
+                pressed: state == ElementState::Pressed,
+            })
+        }
+        Event::MouseMoved((x, y)) => Some(InputEvent::MouseMoved([x as f32, y as f32])),
+        Event::MouseWheel(MouseScrollDelta::LineDelta(_, y)) => Some(InputEvent::MouseWheel(y)),
+        Event::MouseWheel(MouseScrollDelta::PixelDelta(_, y)) => {
+            Some(InputEvent::MouseWheel(y / 16.0))
+        }
+        Event::Focused(false) => Some(InputEvent::FocusLost),
+        _ => None,
+    }
 }
 
-implement_vertex!(Attr, world_pos);
+// every problem with a map's palette and cells, which would otherwise
+// just leave those cells undrawn
+fn validate_map(map: &TileMap, map_path: &Path, tile_set_path: &Path) -> Vec<String> {
+    let ts: Result<TileSet, String> = File::open(tile_set_path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| serde_json::de::from_reader(file).map_err(|err| err.to_string()));
+    match ts {
+        Ok(ts) => map.validate(map_path, &ts).iter().map(|err| err.to_string()).collect(),
+        Err(err) => vec![format!("{}: {}", tile_set_path.display(), err)],
+    }
+}
 
-fn main() {
-    use glium::DisplayBuild;
+/// A grassy field with a stone floored, brick walled room in it.
+fn demo_map(tile_size: [usize; 2]) -> TileMap {
+    let size = [40, 30];
+    let mut map = TileMap::new(size, tile_size);
+    let ground = map.add_layer("ground");
+    let walls = map.add_layer("walls");
+    let grass = map.palette_index("output_tile_formats/floor", "morning_grass");
+    let stone = map.palette_index("output_tile_formats/floor", "morning_stone");
+    let brick = map.palette_index("output_tile_formats/wall", "morning_brick_wall");
 
-    // building the display, ie. the main object
-    let display = glutin::WindowBuilder::new()
-        .build_glium()
-        .unwrap();
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            let inside = x >= 10 && x < 30 && y >= 8 && y < 22;
+            let floor = if inside { stone } else { grass };
+            map.set(ground, [x, y], Cell::new(floor, 0));
+            let edge = x == 10 || x == 29 || y == 8 || y == 21;
+            // leave a door on the south side
+            if inside && edge && !(y == 21 && x >= 19 && x < 21) {
+                map.set(walls, [x, y], Cell::new(brick, 0));
+            }
+        }
+    }
+    map
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut headless = false;
+    let mut ticks = None;
+    let mut script = None;
+    let mut preview = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--headless" => headless = true,
+            "--ticks" => {
+                let n = try!(args.next().ok_or(String::from("--ticks needs a number")));
+                ticks = Some(try!(n.parse::<u64>().map_err(|_| format!("bad tick count `{}`", n))));
+            }
+            "--script" => {
+                script = Some(PathBuf::from(try!(args.next()
+                    .ok_or(String::from("--script needs a path")))));
+            }
+            "--preview" => {
+                preview = Some(PathBuf::from(try!(args.next()
+                    .ok_or(String::from("--preview needs a path")))));
+                headless = true;
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("-") => return Err(format!("unknown option `{}`", arg)),
+            _ => positional.push(PathBuf::from(&arg[..])),
+        }
+    }
+
+    if positional.len() > 2 {
+        return Err(String::from("too many arguments"));
+    }
+    if !headless && (ticks.is_some() || script.is_some()) {
+        return Err(String::from("--ticks and --script only make sense with --headless"));
+    }
+    let map = if positional.len() == 2 { positional.pop() } else { None };
+    let tile_set = positional.pop().unwrap_or(PathBuf::from(DEFAULT_TILE_SET));
 
-    let image = image::load(Cursor::new(&include_bytes!("river-stone.jpg")[..]),
-                            image::JPEG)
-                    .expect("JPEG loading failed")
-                    .to_rgba();
-
-    let image_dimensions = image.dimensions();
-    let image = glium::texture::RawImage2d::from_raw_rgba_reversed(image.into_raw(),
-                                                                   image_dimensions);
-    let texture = glium::texture::CompressedSrgbTexture2d::new(&display, image)
-        .expect("texture creation failed");
-
-    // building the vertex buffer, which contains all the vertices that we will draw
-    let vertex_buffer = {
-        glium::VertexBuffer::new(&display,
-                                 &[Vertex {
-                                       position: [-0.5, -0.5],
-                                       color: [1.0, 0.0, 0.0],
-                                       tex_coords: [0.0, 0.0],
-                                   },
-                                   Vertex {
-                                       position: [0.0, 0.5],
-                                       color: [0.0, 1.0, 0.0],
-                                       tex_coords: [0.0, 1.0],
-                                   },
-                                   Vertex {
-                                       position: [0.5, -0.5],
-                                       color: [0.0, 0.0, 1.0],
-                                       tex_coords: [1.0, 0.0],
-                                   }])
-            .expect("vertex_buffer creation failed")
+    Ok(Options {
+        tile_set: tile_set,
+        map: map,
+        headless: headless,
+        ticks: ticks.unwrap_or(DEFAULT_HEADLESS_TICKS),
+        script: script,
+        preview: preview,
+    })
+}
+
+// runs the scripted ticks, printing the world's final state and writing
+// a preview of it if asked to
+fn run_headless(opts: &Options, mut world: World, ts: &LoadedTileSet, mut input: Input) {
+    let script = match opts.script {
+        Some(ref path) => {
+            Script::load(path).unwrap_or_else(|err| {
+                let _ = writeln!(io::stderr(), "error: {}", err);
+                process::exit(1);
+            })
+        }
+        None => Script::default(),
     };
+    headless::run(&mut world, ts, &mut input, &script, opts.ticks);
+    println!("{}", serde_json::ser::to_string_pretty(&world.dump()).unwrap());
 
-    // building the index buffer
-    let index_buffer = glium::IndexBuffer::new(&display,
-                                               PrimitiveType::TrianglesList,
-                                               &[0u16, 1, 2])
-                           .expect("index_buffer creation failed");
+    if let Some(ref path) = opts.preview {
+        let time_ms = opts.ticks * FIXED_TIME_STEP / 1_000_000;
+        world.map.update(ts, time_ms);
+        let img = software::render(&world.map, ts, &world.camera, &[]);
+        if let Err(err) = img.save(path) {
+            let _ = writeln!(io::stderr(), "error: {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+}
 
-    let triangles = [Attr { world_pos: [0.0, 0.5] },
-                     Attr { world_pos: [-0.5, -0.5] },
-                     Attr { world_pos: [0.5, -0.5] }];
+fn main() {
+    use glium::DisplayBuild;
 
-    let attr_buffer = glium::VertexBuffer::new(&display, &triangles)
-        .expect("attr_buffer creation failed");
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            if msg.is_empty() {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            let _ = writeln!(io::stderr(), "error: {}\n\n{}", msg, USAGE);
+            process::exit(2);
+        }
+    };
 
-    // compiling shaders and linking them together
-    let program = program!(&display,
-        140 => {
-            vertex: include_str!("vertex.140.glsl"),
-            fragment: include_str!("fragment.140.glsl")
+    let mut tile_set = TileSetAsset::load(&opts.tile_set)
+        .unwrap_or_else(|err| panic!("tile set loading failed: {}", err));
+    let map = match opts.map {
+        Some(ref map_path) => {
+            let map = TileMap::load(map_path).unwrap_or_else(|err| panic!("map loading failed: {}", err));
+            let errors = validate_map(&map, map_path, &opts.tile_set);
+            if !errors.is_empty() {
+                for err in &errors {
+                    let _ = writeln!(io::stderr(), "error: {}", err);
+                }
+                process::exit(1);
+            }
+            map
         }
-    )
-                      .expect("shader program creation failed");
+        None => demo_map(tile_set.tile_set.tile_size),
+    };
 
-    let draw_params: DrawParameters = Default::default();
+    let keymap = if Path::new(KEYMAP_PATH).exists() {
+        Keymap::load(Path::new(KEYMAP_PATH))
+            .unwrap_or_else(|err| panic!("keymap loading failed: {}", err))
+    } else {
+        Keymap::default()
+    };
+    let mut input = Input::new(keymap);
 
-    let mut angle = 0.0f32;
+    let step_secs = FIXED_TIME_STEP as f32 / 1_000_000_000.0;
+    if opts.headless {
+        let mut camera = Camera2D::new(HEADLESS_VIEWPORT);
+        camera.set_zoom(ZOOM);
+        let world = World::new(map, &tile_set.tile_set, camera, step_secs);
+        run_headless(&opts, world, &tile_set.tile_set, input);
+        return;
+    }
 
-    const FIXED_TIME_STAMP: u64 = 16_666_667;
-    let mut previous_clock = time::precise_time_ns();
+    // building the display, ie. the main object
+    let display = glutin::WindowBuilder::new()
+        .build_glium()
+        .unwrap();
 
-    // the main loop
-    'mainloop: loop {
+    let mut pages = upload_pages(&display, &tile_set);
+    let mut renderer = TileRenderer::new(&display);
 
-        // building the uniforms
-        let uniforms = uniform! {
-            matrix: [
-                [ angle.cos(), 0.0, angle.sin(), 0.0],
-                [         0.0, 1.0,         0.0, 0.0],
-                [-angle.sin(), 0.0, angle.cos(), 0.0],
-                [         0.0, 0.0,         0.0, 1.0f32]
-            ],
-            tex: &texture,
-        };
-
-        // drawing a frame
-        let mut target = display.draw();
-        target.clear_color(0.0, 0.0, 0.0, 0.0);
-        let per_instance = attr_buffer.per_instance().expect("per_instance() failed");
-        target.draw((&vertex_buffer, per_instance),
-                    &index_buffer,
-                    &program,
-                    &uniforms,
-                    &draw_params)
-              .expect("draw call error");
-        target.finish().expect("frame end error");
+    let (width, height) = display.get_framebuffer_dimensions();
+    let mut camera = Camera2D::new([width, height]);
+    camera.set_zoom(ZOOM);
+    let mut world = World::new(map, &tile_set.tile_set, camera, step_secs);
 
+    let mut clock = SystemClock;
+    let mut stepper = FixedStep::new(FIXED_TIME_STEP, MAX_CATCH_UP_STEPS);
+    let mut last_reload_check = clock.now_ns();
+
+    // the main loop
+    'mainloop: loop {
         // polling and handling the events received by the window
         for event in display.poll_events() {
             match event {
                 glutin::Event::Closed => break 'mainloop,
-                _ => (),
+                _ => {
+                    if let Some(event) = input_event(&event) {
+                        input.handle(&event);
+                    }
+                }
             }
         }
 
-        let now = time::precise_time_ns();
-        let diff = now - previous_clock;
-        previous_clock = now;
-        if diff < FIXED_TIME_STAMP {
-            let ms = ((FIXED_TIME_STAMP - diff) / 1_000_000) as u64;
-            thread::sleep(Duration::from_millis(ms));
+        for _ in 0..stepper.advance(&mut clock) {
+            let actions = input.tick();
+            world.update(&actions, &tile_set.tile_set);
+            // nothing reacts to events yet, so don't let them pile up
+            world.events.clear();
+        }
+
+        let rebuilt = world.map.update(&tile_set.tile_set, stepper.time_ns() / 1_000_000);
+        renderer.upload(&display, &world.map, &rebuilt);
+
+        {
+            // drawing a frame
+            let mut target = display.draw();
+            let (width, height) = target.get_dimensions();
+            world.camera.set_viewport([width, height]);
+            let mut view = world.camera.clone();
+            view.position = lerp(world.previous_camera, world.camera.position, stepper.alpha());
+            target.clear_color(0.0, 0.0, 0.0, 0.0);
+            renderer.draw(&mut target, &world.map, &pages, &view, &[]).expect("draw call error");
+            target.finish().expect("frame end error");
+        }
+
+        let now = clock.now_ns();
+        if now - last_reload_check > RELOAD_CHECK_NS {
+            last_reload_check = now;
+            match tile_set.reload_if_changed() {
+                Ok(true) => {
+                    pages = upload_pages(&display, &tile_set);
+                    world.map.set_tile_set(&tile_set.tile_set);
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    let _ = writeln!(io::stderr(), "couldn't reload tile set: {}", err);
+                }
+            }
         }
-        angle += 1.0 * (diff as f32 / 1_000_000_000.0);
+
+        thread::sleep(Duration::new(0, stepper.until_next_ns() as u32));
     }
 }
@@ -0,0 +1,135 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use glium::{DrawError, IndexBuffer, Program, Surface, VertexBuffer};
+use glium::backend::Facade;
+use glium::draw_parameters::{Blend, DrawParameters};
+use glium::index::PrimitiveType;
+use glium::texture::SrgbTexture2d;
+use glium::uniforms::MagnifySamplerFilter;
+
+use camera::Camera2D;
+use chunks::{layer_tint, ChunkKey, ChunkedMap, TileInstance};
+
+#[derive(Copy, Clone)]
+struct Corner {
+    corner: [f32; 2],
+}
+
+implement_vertex!(Corner, corner);
+implement_vertex!(TileInstance, world_pos, tex_min, tex_max, transposed);
+
+/// Draws a `ChunkedMap`, keeping one instance buffer per chunk and page.
+pub struct TileRenderer {
+    quad: VertexBuffer<Corner>,
+    indices: IndexBuffer<u16>,
+    program: Program,
+    chunks: HashMap<ChunkKey, Vec<(usize, VertexBuffer<TileInstance>)>>,
+}
+
+impl TileRenderer {
+    pub fn new<F: Facade>(display: &F) -> TileRenderer {
+        let quad = VertexBuffer::new(display,
+                                     &[Corner { corner: [0.0, 0.0] },
+                                       Corner { corner: [1.0, 0.0] },
+                                       Corner { corner: [0.0, 1.0] },
+                                       Corner { corner: [1.0, 1.0] }])
+            .expect("quad creation failed");
+        let indices = IndexBuffer::new(display, PrimitiveType::TrianglesList, &[0u16, 1, 2, 2, 1, 3])
+            .expect("index buffer creation failed");
+        let program = program!(display,
+            140 => {
+                vertex: include_str!("vertex.140.glsl"),
+                fragment: include_str!("fragment.140.glsl")
+            }
+        )
+                          .expect("shader program creation failed");
+
+        TileRenderer {
+            quad: quad,
+            indices: indices,
+            program: program,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Uploads the chunks `ChunkedMap::update` rebuilt.
+    pub fn upload<F: Facade>(&mut self, display: &F, map: &ChunkedMap, rebuilt: &[ChunkKey]) {
+        for &key in rebuilt {
+            let mesh = map.mesh(key);
+            if mesh.is_empty() {
+                self.chunks.remove(&key);
+                continue;
+            }
+            let buffers = mesh.pages
+                .iter()
+                .map(|&(page, ref instances)| {
+                    let buffer = VertexBuffer::new(display, instances)
+                        .expect("instance buffer creation failed");
+                    (page, buffer)
+                })
+                .collect();
+            self.chunks.insert(key, buffers);
+        }
+    }
+
+    /// Draws every layer of the chunks the camera sees, each multiplied
+    /// by its tint.
+    pub fn draw<S: Surface>(&self,
+                            target: &mut S,
+                            map: &ChunkedMap,
+                            pages: &[SrgbTexture2d],
+                            camera: &Camera2D,
+                            tints: &[[f32; 4]])
+                            -> Result<(), DrawError> {
+        let tile_size = map.map().tile_size;
+        let matrix = camera.matrix();
+        let params = DrawParameters { blend: Blend::alpha_blending(), ..Default::default() };
+        let (view_min, view_max) = camera.visible_rect();
+        let visible = map.visible(view_min, view_max);
+
+        for layer in 0..map.map().layers.len() {
+            let tint = layer_tint(tints, layer);
+            for &chunk in &visible {
+                let key = ChunkKey {
+                    layer: layer,
+                    chunk: chunk,
+                };
+                let buffers = match self.chunks.get(&key) {
+                    Some(buffers) => buffers,
+                    None => continue,
+                };
+                for &(page, ref buffer) in buffers {
+                    let uniforms = uniform! {
+                        matrix: matrix,
+                        tile_size: [tile_size[0] as f32, tile_size[1] as f32],
+                        tint: tint,
+                        tex: pages[page].sampled().magnify_filter(MagnifySamplerFilter::Nearest),
+                    };
+                    let per_instance = buffer.per_instance().expect("per_instance() failed");
+                    try!(target.draw((&self.quad, per_instance),
+                                     &self.indices,
+                                     &self.program,
+                                     &uniforms,
+                                     &params));
+                }
+            }
+        }
+        Ok(())
+    }
+}
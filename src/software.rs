@@ -0,0 +1,297 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use chickpea_tiles::LoadedTileSet;
+use image::{Rgba, RgbaImage};
+
+use camera::Camera2D;
+use chunks::{layer_tint, ChunkKey, ChunkedMap, TileInstance};
+
+/// Renders what the camera sees into an image the size of its viewport,
+/// the same way the tile shader does: every layer's chunks as they were
+/// last updated, nearest sampled, tinted and alpha blended in order.
+pub fn render(map: &ChunkedMap, ts: &LoadedTileSet, camera: &Camera2D, tints: &[[f32; 4]]) -> RgbaImage {
+    let viewport = camera.viewport();
+    let mut img = RgbaImage::new(viewport[0], viewport[1]);
+    let (view_min, view_max) = camera.visible_rect();
+    let visible = map.visible(view_min, view_max);
+
+    for layer in 0..map.map().layers.len() {
+        let tint = layer_tint(tints, layer);
+        for &chunk in &visible {
+            let key = ChunkKey {
+                layer: layer,
+                chunk: chunk,
+            };
+            for &(page, ref instances) in &map.mesh(key).pages {
+                for instance in instances {
+                    draw_tile(&mut img, &ts.pages[page], map.map().tile_size, camera, instance, tint);
+                }
+            }
+        }
+    }
+    img
+}
+
+fn draw_tile(img: &mut RgbaImage,
+             page: &RgbaImage,
+             tile_size: [usize; 2],
+             camera: &Camera2D,
+             instance: &TileInstance,
+             tint: [f32; 4]) {
+    let zoom = camera.zoom() as f32;
+    let size = [tile_size[0] as f32 * zoom, tile_size[1] as f32 * zoom];
+    let origin = camera.world_to_screen(instance.world_pos);
+    let (img_w, img_h) = img.dimensions();
+    let (page_w, page_h) = page.dimensions();
+
+    let x0 = origin[0].floor().max(0.0) as u32;
+    let y0 = origin[1].floor().max(0.0) as u32;
+    let x1 = ((origin[0] + size[0]).ceil().max(0.0) as u32).min(img_w);
+    let y1 = ((origin[1] + size[1]).ceil().max(0.0) as u32).min(img_h);
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            // where the pixel's center falls on the quad, from 0 to 1
+            let corner = [(x as f32 + 0.5 - origin[0]) / size[0], (y as f32 + 0.5 - origin[1]) / size[1]];
+            let source = if instance.transposed > 0.5 {
+                [corner[1], corner[0]]
+            } else {
+                corner
+            };
+            let u = instance.tex_min[0] + (instance.tex_max[0] - instance.tex_min[0]) * source[0];
+            let v = instance.tex_min[1] + (instance.tex_max[1] - instance.tex_min[1]) * source[1];
+            let tx = ((u * page_w as f32).floor().max(0.0) as u32).min(page_w - 1);
+            let ty = ((v * page_h as f32).floor().max(0.0) as u32).min(page_h - 1);
+
+            let texel = page.get_pixel(tx, ty).data;
+            let mut src = [0.0; 4];
+            for c in 0..3 {
+                src[c] = srgb_to_linear(texel[c]) * tint[c];
+            }
+            src[3] = texel[3] as f32 / 255.0 * tint[3];
+            blend(img.get_pixel_mut(x, y), src);
+        }
+    }
+}
+
+// the atlas pages are sRGB textures, so the shader tints and blends in
+// linear space and the framebuffer encodes the result back to sRGB
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.max(0.0).min(1.0);
+    let s = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (s * 255.0).round().max(0.0).min(255.0) as u8
+}
+
+// `Blend::alpha_blending`: every channel, alpha included, is
+// `src * src_alpha + dst * (1 - src_alpha)`
+fn blend(dst: &mut Rgba<u8>, src: [f32; 4]) {
+    for c in 0..3 {
+        let d = srgb_to_linear(dst.data[c]);
+        dst.data[c] = linear_to_srgb(src[c] * src[3] + d * (1.0 - src[3]));
+    }
+    let dst_a = dst.data[3] as f32 / 255.0;
+    let out_a = src[3] * src[3] + dst_a * (1.0 - src[3]);
+    dst.data[3] = (out_a * 255.0).round().max(0.0).min(255.0) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chickpea_tiles::{LoadedTileSet, TileMap};
+    use chickpea_tiles::map::{Cell, FLIP_DIAGONAL, FLIP_HORIZONTAL};
+    use image::{Rgba, RgbaImage};
+    use camera::Camera2D;
+    use chunks::ChunkedMap;
+    use test_data::morning;
+
+    const FLOOR: &'static str = "output_tile_formats/floor";
+    const WALL: &'static str = "output_tile_formats/wall";
+
+    // a row of `cells` on the ground layer, with a wall layer above it
+    fn row(ts: &LoadedTileSet, cells: &[(&str, &str, u32)], walls: &[usize]) -> ChunkedMap {
+        let mut map = TileMap::new([cells.len(), 1], [16, 16]);
+        let ground = map.add_layer("ground");
+        let wall_layer = map.add_layer("walls");
+        for (x, &(fmt, id, flips)) in cells.iter().enumerate() {
+            let item = map.palette_index(fmt, id);
+            map.set(ground, [x, 0], Cell::new(item, flips));
+        }
+        let brick = map.palette_index(WALL, "morning_brick_wall");
+        for &x in walls {
+            map.set(wall_layer, [x, 0], Cell::new(brick, 0));
+        }
+        let mut chunked = ChunkedMap::new(map, ts);
+        chunked.update(ts, 0);
+        chunked
+    }
+
+    fn camera(viewport: [u32; 2], zoom: u32, map: &ChunkedMap) -> Camera2D {
+        let mut camera = Camera2D::new(viewport);
+        camera.set_zoom(zoom);
+        let size = map.map().size;
+        camera.set_bounds([0.0, 0.0], [size[0] as f32 * 16.0, size[1] as f32 * 16.0]);
+        camera
+    }
+
+    // the tile a cell draws, cut out of its atlas page
+    fn atlas_tile(ts: &LoadedTileSet, map: &ChunkedMap, layer: usize, pos: [usize; 2]) -> RgbaImage {
+        let (tile, part, index) = map.cell_tile(ts, layer, pos).unwrap();
+        let rect = ts.rect(tile, part, index).unwrap();
+        let page = &ts.pages[rect.page];
+        RgbaImage::from_fn(rect.w as u32,
+                           rect.h as u32,
+                           |x, y| *page.get_pixel(rect.x as u32 + x, rect.y as u32 + y))
+    }
+
+    #[test]
+    fn tiles_copy_their_atlas_pixels() {
+        let ts = morning("software_atlas");
+        let map = row(&ts, &[(FLOOR, "morning_stone", 0), (FLOOR, "morning_grass", 0)], &[]);
+        let img = render(&map, &ts, &camera([64, 32], 2, &map), &[]);
+        assert_eq!(img.dimensions(), (64, 32));
+
+        let stone = atlas_tile(&ts, &map, 0, [0, 0]);
+        let grass = atlas_tile(&ts, &map, 0, [1, 0]);
+        for y in 0..32 {
+            for x in 0..64 {
+                let tile = if x < 32 { &stone } else { &grass };
+                let texel = tile.get_pixel(x % 32 / 2, y / 2);
+                // anything less than opaque is blended with the empty image
+                if texel.data[3] == 255 {
+                    assert_eq!(img.get_pixel(x, y), texel);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flips_match_the_shader() {
+        let ts = morning("software_flips");
+        let plain = row(&ts, &[(FLOOR, "morning_stone", 0)], &[]);
+        let mirrored = row(&ts, &[(FLOOR, "morning_stone", FLIP_HORIZONTAL)], &[]);
+        let transposed = row(&ts, &[(FLOOR, "morning_stone", FLIP_DIAGONAL)], &[]);
+
+        let view = camera([16, 16], 1, &plain);
+        let plain = render(&plain, &ts, &view, &[]);
+        let mirrored = render(&mirrored, &ts, &view, &[]);
+        let transposed = render(&transposed, &ts, &view, &[]);
+        for y in 0..16 {
+            for x in 0..16 {
+                assert_eq!(mirrored.get_pixel(x, y), plain.get_pixel(15 - x, y));
+                assert_eq!(transposed.get_pixel(x, y), plain.get_pixel(y, x));
+            }
+        }
+    }
+
+    #[test]
+    fn layers_blend_in_order_with_their_tints() {
+        let ts = morning("software_layers");
+        let map = row(&ts, &[(FLOOR, "morning_stone", 0)], &[0]);
+        let view = camera([16, 16], 1, &map);
+        let stone = atlas_tile(&ts, &map, 0, [0, 0]);
+        let brick = atlas_tile(&ts, &map, 1, [0, 0]);
+
+        let img = render(&map, &ts, &view, &[]);
+        let hidden = [0.0, 0.0, 0.0, 0.0];
+        let red = [1.0, 0.0, 0.0, 1.0];
+        let tinted = render(&map, &ts, &view, &[hidden, red]);
+        for y in 0..16 {
+            for x in 0..16 {
+                let b = brick.get_pixel(x, y).data;
+                if b[3] == 255 {
+                    assert_eq!(img.get_pixel(x, y), brick.get_pixel(x, y));
+                    assert_eq!(tinted.get_pixel(x, y).data, [b[0], 0, 0, 255]);
+                } else if b[3] == 0 {
+                    assert_eq!(img.get_pixel(x, y), stone.get_pixel(x, y));
+                    assert_eq!(tinted.get_pixel(x, y).data[3], 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tints_and_blending_happen_in_linear_space() {
+        // half of white at half alpha over black; mixing sRGB bytes gives 64
+        let mut pixel = Rgba { data: [0, 0, 0, 255] };
+        blend(&mut pixel, [0.5, 0.5, 0.5, 0.5]);
+        assert_eq!(pixel.data, [137, 137, 137, 191]);
+
+        let ts = morning("software_linear");
+        let map = row(&ts, &[(FLOOR, "morning_stone", 0)], &[0]);
+        let view = camera([16, 16], 1, &map);
+        let stone = atlas_tile(&ts, &map, 0, [0, 0]);
+        let brick = atlas_tile(&ts, &map, 1, [0, 0]);
+
+        let half = [0.5, 0.5, 0.5, 0.5];
+        let img = render(&map, &ts, &view, &[[1.0, 1.0, 1.0, 1.0], half]);
+        for y in 0..16 {
+            for x in 0..16 {
+                let b = brick.get_pixel(x, y).data;
+                let s = stone.get_pixel(x, y).data;
+                if b[3] == 255 && s[3] == 255 {
+                    let mut expected = [0, 0, 0, 191];
+                    for c in 0..3 {
+                        let linear = srgb_to_linear(b[c]) * 0.25 + srgb_to_linear(s[c]) * 0.5;
+                        expected[c] = linear_to_srgb(linear);
+                    }
+                    assert_eq!(img.get_pixel(x, y).data, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_camera_moves_the_picture() {
+        let ts = morning("software_camera");
+        let map = row(&ts,
+                      &[(FLOOR, "morning_stone", 0), (FLOOR, "morning_grass", 0), (FLOOR, "morning_stone", 0)],
+                      &[]);
+        let mut view = camera([16, 16], 1, &map);
+        view.look_at([8.0, 8.0]);
+        let left = render(&map, &ts, &view, &[]);
+        view.pan([8.0, 0.0]);
+        let panned = render(&map, &ts, &view, &[]);
+
+        // the world pixel under every screen pixel is the one the camera says
+        for y in 0..16 {
+            for x in 0..8 {
+                assert_eq!(panned.get_pixel(x, y), left.get_pixel(x + 8, y));
+            }
+        }
+        let grass = atlas_tile(&ts, &map, 0, [1, 0]);
+        for y in 0..16 {
+            for x in 8..16 {
+                let world = view.screen_to_world([x as f32, y as f32]);
+                assert_eq!(panned.get_pixel(x, y),
+                           grass.get_pixel(world[0] as u32 - 16, world[1] as u32));
+            }
+        }
+    }
+}
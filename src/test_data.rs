@@ -0,0 +1,40 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::Path;
+
+use chickpea_tiles::{compile_tile_set, LoadedTileSet};
+
+/// Compiles the morning tile set under its own name, so tests running in
+/// parallel don't overwrite each other's pages, and loads it.
+pub fn morning(name: &str) -> LoadedTileSet {
+    compile("tile_set_sources/morning", name)
+}
+
+/// Like `morning`, for the animated water autotiles.
+pub fn blob(name: &str) -> LoadedTileSet {
+    compile("tile_set_sources/blob", name)
+}
+
+fn compile(source: &str, name: &str) -> LoadedTileSet {
+    compile_tile_set(&Path::new("chickpea_tiles/test_data/src"),
+                     &Path::new(source),
+                     &Path::new("chickpea_tiles/test_data/target"),
+                     &Path::new(&format!("tile_sets/{}", name)))
+        .expect("compilation failed");
+    LoadedTileSet::open(&Path::new(&format!("chickpea_tiles/test_data/target/tile_sets/{}.json", name)))
+        .expect("couldn't load tile set")
+}
@@ -17,17 +17,19 @@
 #version 140
 
 uniform mat4 matrix;
+uniform vec2 tile_size;
+
+in vec2 corner;
 
 in vec2 world_pos;
-in vec2 position;
-in vec3 color;
-in vec2 tex_coords;
+in vec2 tex_min;
+in vec2 tex_max;
+in float transposed;
 
 out vec2 v_tex_coords;
-out vec3 v_color;
 
 void main() {
-    gl_Position = vec4(position + world_pos, 0.0, 1.0) * matrix;
-    v_tex_coords = tex_coords;
-    v_color = color;
+    vec2 source = transposed > 0.5 ? corner.yx : corner;
+    v_tex_coords = mix(tex_min, tex_max, source);
+    gl_Position = matrix * vec4(world_pos + corner * tile_size, 0.0, 1.0);
 }
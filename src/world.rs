@@ -0,0 +1,304 @@
+// chickpea, A small tile-based game project
+// Copyright (C) 2016 Emily A. Bellows
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+
+use chickpea_tiles::{LoadedTileSet, TileMap};
+use serde_json::value::Value;
+
+use camera::Camera2D;
+use chunks::ChunkedMap;
+use input::{Action, ActionState};
+
+/// Ticks between steps while a move action is held.
+pub const MOVE_TICKS: u32 = 8;
+/// World pixels the camera pans every tick.
+pub const PAN_SPEED: f32 = 4.0;
+
+/// Something the player did, for whatever reacts to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorldEvent {
+    pub tick: u64,
+    pub action: Action,
+    /// The cell the player was facing.
+    pub pos: [i32; 2],
+}
+
+/// The game state, advanced one fixed tick at a time. Nothing in here
+/// needs a window, so it runs just as well headless.
+pub struct World {
+    pub map: ChunkedMap,
+    pub camera: Camera2D,
+    /// The camera's position before the last tick, for interpolating.
+    pub previous_camera: [f32; 2],
+    pub player: [usize; 2],
+    pub facing: [i32; 2],
+    pub tick: u64,
+    pub events: Vec<WorldEvent>,
+    step_secs: f32,
+    move_cooldown: u32,
+    // set by panning, cleared by moving the player
+    free_camera: bool,
+}
+
+impl World {
+    pub fn new(map: TileMap, ts: &LoadedTileSet, mut camera: Camera2D, step_secs: f32) -> World {
+        let map_size = [(map.size[0] * map.tile_size[0]) as f32,
+                        (map.size[1] * map.tile_size[1]) as f32];
+        camera.set_bounds([0.0, 0.0], map_size);
+        let map = ChunkedMap::new(map, ts);
+
+        let mut world = World {
+            map: map,
+            previous_camera: camera.position,
+            camera: camera,
+            player: [0, 0],
+            facing: [0, 1],
+            tick: 0,
+            events: Vec::new(),
+            step_secs: step_secs,
+            move_cooldown: 0,
+            free_camera: false,
+        };
+        world.player = world.spawn_point(ts);
+        let target = world.player_center();
+        world.camera.look_at(target);
+        world.previous_camera = world.camera.position;
+        world
+    }
+
+    // the map's center if it's free, otherwise the first free cell
+    fn spawn_point(&self, ts: &LoadedTileSet) -> [usize; 2] {
+        let size = self.map.map().size;
+        let center = [size[0] / 2, size[1] / 2];
+        if !self.is_solid(ts, center) {
+            return center;
+        }
+        for y in 0..size[1] {
+            for x in 0..size[0] {
+                if !self.is_solid(ts, [x, y]) {
+                    return [x, y];
+                }
+            }
+        }
+        center
+    }
+
+    /// Whether a tile on any layer of the cell is solid.
+    pub fn is_solid(&self, ts: &LoadedTileSet, pos: [usize; 2]) -> bool {
+        (0..self.map.map().layers.len()).any(|layer| {
+            match self.map.cell_tile(ts, layer, pos) {
+                Some((tile, part, _)) => {
+                    ts.properties(tile, part).and_then(|props| props.solid).unwrap_or(false)
+                }
+                None => false,
+            }
+        })
+    }
+
+    pub fn player_center(&self) -> [f32; 2] {
+        let tile_size = self.map.map().tile_size;
+        [(self.player[0] * tile_size[0]) as f32 + tile_size[0] as f32 / 2.0,
+         (self.player[1] * tile_size[1]) as f32 + tile_size[1] as f32 / 2.0]
+    }
+
+    fn facing_cell(&self) -> [i32; 2] {
+        [self.player[0] as i32 + self.facing[0], self.player[1] as i32 + self.facing[1]]
+    }
+
+    pub fn update(&mut self, actions: &ActionState, ts: &LoadedTileSet) {
+        self.previous_camera = self.camera.position;
+
+        let movement = actions.movement();
+        if movement == [0, 0] {
+            self.move_cooldown = 0;
+        } else {
+            // no diagonal steps, horizontal wins
+            self.facing = if movement[0] != 0 {
+                [movement[0], 0]
+            } else {
+                [0, movement[1]]
+            };
+            self.free_camera = false;
+            if self.move_cooldown == 0 {
+                let target = self.facing_cell();
+                let size = self.map.map().size;
+                if target[0] >= 0 && target[1] >= 0 && (target[0] as usize) < size[0] &&
+                   (target[1] as usize) < size[1] &&
+                   !self.is_solid(ts, [target[0] as usize, target[1] as usize]) {
+                    self.player = [target[0] as usize, target[1] as usize];
+                }
+                self.move_cooldown = MOVE_TICKS;
+            }
+            self.move_cooldown -= 1;
+        }
+
+        for &action in &[Action::Confirm, Action::Cancel] {
+            if actions.just_pressed(action) {
+                let pos = self.facing_cell();
+                self.events.push(WorldEvent {
+                    tick: self.tick,
+                    action: action,
+                    pos: pos,
+                });
+            }
+        }
+
+        let pan = actions.panning();
+        if pan != [0, 0] || actions.drag != [0.0, 0.0] {
+            self.free_camera = true;
+            let zoom = self.camera.zoom() as f32;
+            self.camera.pan([pan[0] as f32 * PAN_SPEED - actions.drag[0] / zoom,
+                             pan[1] as f32 * PAN_SPEED - actions.drag[1] / zoom]);
+        }
+        if actions.just_pressed(Action::ZoomIn) || actions.wheel > 0.0 {
+            self.camera.zoom_in();
+        }
+        if actions.just_pressed(Action::ZoomOut) || actions.wheel < 0.0 {
+            self.camera.zoom_out();
+        }
+        if !self.free_camera {
+            let target = self.player_center();
+            self.camera.follow(target, self.step_secs);
+        }
+
+        self.tick += 1;
+    }
+
+    /// The state worth checking from outside, as JSON.
+    pub fn dump(&self) -> Value {
+        let pair_i = |p: [i32; 2]| Value::Array(vec![Value::I64(p[0] as i64), Value::I64(p[1] as i64)]);
+        let pair_f = |p: [f32; 2]| Value::Array(vec![Value::F64(p[0] as f64), Value::F64(p[1] as f64)]);
+
+        let mut camera = BTreeMap::new();
+        camera.insert(String::from("position"), pair_f(self.camera.position));
+        camera.insert(String::from("zoom"), Value::U64(self.camera.zoom() as u64));
+
+        let events = self.events
+            .iter()
+            .map(|event| {
+                let mut obj = BTreeMap::new();
+                obj.insert(String::from("tick"), Value::U64(event.tick));
+                obj.insert(String::from("action"), Value::String(String::from(event.action.name())));
+                obj.insert(String::from("pos"), pair_i(event.pos));
+                Value::Object(obj)
+            })
+            .collect();
+
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("tick"), Value::U64(self.tick));
+        obj.insert(String::from("player"),
+                   pair_i([self.player[0] as i32, self.player[1] as i32]));
+        obj.insert(String::from("facing"), pair_i(self.facing));
+        obj.insert(String::from("camera"), Value::Object(camera));
+        obj.insert(String::from("events"), Value::Array(events));
+        Value::Object(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chickpea_tiles::{LoadedTileSet, TileMap};
+    use chickpea_tiles::map::Cell;
+    use camera::Camera2D;
+    use input::{Action, Input, InputEvent, Keymap};
+    use test_data::morning;
+
+    // a 10x6 field of grass with a wall down column 7
+    fn walled_field(ts: &LoadedTileSet) -> World {
+        let mut map = TileMap::new([10, 6], [16, 16]);
+        let ground = map.add_layer("ground");
+        let walls = map.add_layer("walls");
+        let grass = map.palette_index("output_tile_formats/floor", "morning_grass");
+        let brick = map.palette_index("output_tile_formats/wall", "morning_brick_wall");
+        for y in 0..6 {
+            for x in 0..10 {
+                map.set(ground, [x, y], Cell::new(grass, 0));
+            }
+            map.set(walls, [7, y], Cell::new(brick, 0));
+        }
+        World::new(map, ts, Camera2D::new([64, 64]), 1.0 / 60.0)
+    }
+
+    fn step(world: &mut World, ts: &LoadedTileSet, input: &mut Input, events: &[InputEvent]) {
+        for event in events {
+            input.handle(event);
+        }
+        let actions = input.tick();
+        world.update(&actions, ts);
+    }
+
+    #[test]
+    fn walls_block_the_player() {
+        let ts = morning("world_walls");
+        let mut world = walled_field(&ts);
+        let mut input = Input::new(Keymap::default());
+        assert_eq!(world.player, [5, 3]);
+        assert!(world.is_solid(&ts, [7, 0]) && !world.is_solid(&ts, [6, 0]));
+
+        step(&mut world, &ts, &mut input, &[InputEvent::press("Right")]);
+        assert_eq!(world.player, [6, 3]);
+        // held, the player steps again every MOVE_TICKS ticks
+        for _ in 1..MOVE_TICKS {
+            step(&mut world, &ts, &mut input, &[]);
+        }
+        assert_eq!(world.player, [6, 3]);
+        for _ in 0..MOVE_TICKS * 3 {
+            step(&mut world, &ts, &mut input, &[]);
+        }
+        assert_eq!(world.player, [6, 3]);
+        assert_eq!(world.facing, [1, 0]);
+
+        step(&mut world,
+             &ts,
+             &mut input,
+             &[InputEvent::release("Right"), InputEvent::press("Return")]);
+        assert_eq!(world.events,
+                   vec![WorldEvent {
+                            tick: MOVE_TICKS as u64 * 4,
+                            action: Action::Confirm,
+                            pos: [7, 3],
+                        }]);
+    }
+
+    #[test]
+    fn the_camera_follows_until_panned() {
+        let ts = morning("world_camera");
+        let mut world = walled_field(&ts);
+        let mut input = Input::new(Keymap::default());
+        assert_eq!(world.camera.position, [88.0, 56.0]);
+
+        step(&mut world, &ts, &mut input, &[InputEvent::press("Left")]);
+        step(&mut world, &ts, &mut input, &[InputEvent::release("Left")]);
+        assert_eq!(world.player, [4, 3]);
+        for _ in 0..120 {
+            step(&mut world, &ts, &mut input, &[]);
+        }
+        assert!((world.camera.position[0] - 72.0).abs() < 0.01);
+
+        let followed = world.camera.position[0];
+        step(&mut world, &ts, &mut input, &[InputEvent::press("L")]);
+        assert_eq!(world.camera.position[0], followed + PAN_SPEED);
+        assert_eq!(world.previous_camera[0], followed);
+        // once panned, the camera stays put
+        step(&mut world, &ts, &mut input, &[InputEvent::release("L")]);
+        for _ in 0..10 {
+            step(&mut world, &ts, &mut input, &[]);
+        }
+        assert_eq!(world.camera.position[0], followed + PAN_SPEED);
+    }
+}